@@ -1,7 +1,10 @@
 use winapi::OVERLAPPED;
 use winapi::HANDLE;
+use winapi::DWORD;
 use std::ptr::null_mut;
 
+use operation_slab::Token;
+
 // -----------------------------------------------------------------------------
 pub struct WriteData {
     pub bytes_to_write: usize,
@@ -14,16 +17,59 @@ pub struct ReadData {
     pub callback: Box<Fn(Result<&[u8], String>)>,
 }
 
+// -----------------------------------------------------------------------------
+// A single read at the offset of the overlapped, the callback receives what was
+// read, shorter than the buffer at the end of the file.
+pub struct ReadAtData {
+    pub callback: Box<Fn(Result<&[u8], String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// A single read into a buffer owned by the caller, which must stay valid until
+// the callback receives the number of bytes read.
+pub struct ReadIntoData {
+    pub callback: Box<Fn(Result<usize, String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// A single write from a buffer owned by the caller, which must stay valid
+// until the callback receives the number of bytes written.
+pub struct WriteFromData {
+    pub callback: Box<Fn(Result<usize, String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// A lock of the range starting at the offset of the overlapped, completed once
+// it is taken.
+pub struct LockData {
+    pub callback: Box<Fn(Result<(), String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// Blocking work run on an io worker. It receives an error instead if it cannot
+// be posted to the workers.
+pub struct TaskData {
+    pub task: Box<Fn(Result<(), String>)>,
+}
+
 // -----------------------------------------------------------------------------
 pub enum DataType {
     Write(WriteData),
+    WriteFrom(WriteFromData),
     Read(ReadData),
+    ReadAt(ReadAtData),
+    ReadInto(ReadIntoData),
+    Lock(LockData),
+    Task(TaskData),
 }
 
 // -----------------------------------------------------------------------------
-#[repr(C)]
+// The worker reaches the token of a completed operation from its OVERLAPPED,
+// see token_of.
 pub struct AsyncData {
     pub overlapped: OVERLAPPED,
+    // Set when the operation is inserted in the slab.
+    pub token: Token,
     pub file_handle: HANDLE,
     pub buffer: Vec<u8>,
     pub data_type: DataType,
@@ -40,6 +86,7 @@ impl AsyncData {
                           	println!("Create async data w");
         AsyncData {
             overlapped: AsyncData::create_overlapped(),
+            token: 0,
             file_handle: file_handle,
             buffer: buffer,
             data_type: DataType::Write(WriteData {
@@ -49,6 +96,24 @@ impl AsyncData {
         }
     }
 
+    // -------------------------------------------------------------------------
+    pub fn new_write_from_data(file_handle: HANDLE,
+                               offset: u64,
+                               callback: Box<Fn(Result<usize, String>)>)
+                               -> AsyncData {
+        let mut overlapped = AsyncData::create_overlapped();
+
+        overlapped.Offset = offset as DWORD;
+        overlapped.OffsetHigh = (offset >> 32) as DWORD;
+        AsyncData {
+            overlapped: overlapped,
+            token: 0,
+            file_handle: file_handle,
+            buffer: Vec::new(),
+            data_type: DataType::WriteFrom(WriteFromData { callback: callback }),
+        }
+    }
+
     // -------------------------------------------------------------------------
     pub fn new_read_data(file_handle: HANDLE,
                          read_size: usize,
@@ -62,6 +127,7 @@ impl AsyncData {
 
         AsyncData {
             overlapped: AsyncData::create_overlapped(),
+            token: 0,
             file_handle: file_handle,
             buffer: buffer,
             data_type: DataType::Read(ReadData {
@@ -71,11 +137,82 @@ impl AsyncData {
         }
     }
 
+    // -------------------------------------------------------------------------
+    pub fn new_read_at_data(file_handle: HANDLE,
+                            offset: u64,
+                            read_size: usize,
+                            callback: Box<Fn(Result<&[u8], String>)>)
+                            -> AsyncData {
+        let mut overlapped = AsyncData::create_overlapped();
+
+        overlapped.Offset = offset as DWORD;
+        overlapped.OffsetHigh = (offset >> 32) as DWORD;
+        AsyncData {
+            overlapped: overlapped,
+            token: 0,
+            file_handle: file_handle,
+            buffer: vec![0; read_size],
+            data_type: DataType::ReadAt(ReadAtData { callback: callback }),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn new_read_into_data(file_handle: HANDLE,
+                              offset: u64,
+                              callback: Box<Fn(Result<usize, String>)>)
+                              -> AsyncData {
+        let mut overlapped = AsyncData::create_overlapped();
+
+        overlapped.Offset = offset as DWORD;
+        overlapped.OffsetHigh = (offset >> 32) as DWORD;
+        AsyncData {
+            overlapped: overlapped,
+            token: 0,
+            file_handle: file_handle,
+            buffer: Vec::new(),
+            data_type: DataType::ReadInto(ReadIntoData { callback: callback }),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn new_lock_data(file_handle: HANDLE,
+                         offset: u64,
+                         callback: Box<Fn(Result<(), String>)>)
+                         -> AsyncData {
+        let mut overlapped = AsyncData::create_overlapped();
+
+        overlapped.Offset = offset as DWORD;
+        overlapped.OffsetHigh = (offset >> 32) as DWORD;
+        AsyncData {
+            overlapped: overlapped,
+            token: 0,
+            file_handle: file_handle,
+            buffer: Vec::new(),
+            data_type: DataType::Lock(LockData { callback: callback }),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn new_task_data(task: Box<Fn(Result<(), String>)>) -> AsyncData {
+        AsyncData {
+            overlapped: AsyncData::create_overlapped(),
+            token: 0,
+            file_handle: null_mut(),
+            buffer: Vec::new(),
+            data_type: DataType::Task(TaskData { task: task }),
+        }
+    }
+
 	// -------------------------------------------------------------------------
     pub fn execute_error_callback(&self, error: String) {
     	match &self.data_type {
     		&DataType::Read(ref read_data) => read_data.callback.as_ref()(Err(error)), 
-    		&DataType::Write(ref write_data) => write_data.callback.as_ref()(Err(error))
+    		&DataType::ReadAt(ref read_at_data) => read_at_data.callback.as_ref()(Err(error)),
+    		&DataType::ReadInto(ref read_into_data) => read_into_data.callback.as_ref()(Err(error)),
+    		&DataType::Write(ref write_data) => write_data.callback.as_ref()(Err(error)),
+    		&DataType::WriteFrom(ref write_from_data) => write_from_data.callback.as_ref()(Err(error)),
+    		&DataType::Lock(ref lock_data) => lock_data.callback.as_ref()(Err(error)),
+    		&DataType::Task(ref task_data) => task_data.task.as_ref()(Err(error))
     	}
     }
     
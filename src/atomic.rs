@@ -0,0 +1,155 @@
+use file::File;
+use file::set_file_len;
+
+use tools::post_task;
+
+use win_api_helper::flush_file_buffers;
+use win_api_helper::move_file_ex;
+use win_api_helper::write_file_blocking;
+
+use winapi::MOVEFILE_REPLACE_EXISTING;
+use winapi::MOVEFILE_WRITE_THROUGH;
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+const WRITE_CHUNK_SIZE: usize = 1 << 30;
+
+// -----------------------------------------------------------------------------
+// Writes a file which replaces the one at path only once complete: readers
+// see either the old content or the new one. The temporary file is removed
+// if the AtomicFile is dropped without commit.
+pub struct AtomicFile {
+    file: Option<File>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+// -----------------------------------------------------------------------------
+impl AtomicFile {
+    // -------------------------------------------------------------------------
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<AtomicFile, String> {
+        let path = path.as_ref().to_path_buf();
+        let temp_path = temp_path_for(&path);
+        let file = try!(File::create(&temp_path));
+
+        Ok(AtomicFile {
+            file: Some(file),
+            temp_path: temp_path,
+            path: path,
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // The temporary file, the writes must be completed before commit.
+    pub fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("AtomicFile already committed")
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn commit(mut self, callback: Box<Fn(Result<(), String>)>) {
+        let file = RefCell::new(self.file.take());
+        let temp_path = self.temp_path.clone();
+        let path = self.path.clone();
+
+        post_task(Box::new(move |result| {
+            let file = file.borrow_mut().take();
+
+            callback.as_ref()(result.and_then(|_| {
+                replace(file.expect("AtomicFile committed twice"), &temp_path, &path)
+            }));
+        }));
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            drop(file);
+            let _ = ::std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Replaces the file at path by data, see AtomicFile.
+pub fn atomic_write<P: AsRef<Path>>(path: P,
+                                    data: Vec<u8>,
+                                    callback: Box<Fn(Result<(), String>)>) {
+    let path = path.as_ref().to_path_buf();
+
+    post_task(Box::new(move |result| {
+        callback.as_ref()(result.and_then(|_| {
+            let temp_path = temp_path_for(&path);
+            let file = try!(File::create(&temp_path));
+
+            match write_data(&file, &data) {
+                Ok(_) => replace(file, &temp_path, &path),
+                Err(error) => {
+                    drop(file);
+                    let _ = ::std::fs::remove_file(&temp_path);
+                    Err(error)
+                }
+            }
+        }));
+    }));
+}
+
+// -----------------------------------------------------------------------------
+// A unique name in the same directory, so the rename stays on the volume.
+pub(crate) fn temp_path_for(path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+
+    file_name.push(path.file_name().unwrap_or(path.as_os_str()));
+    file_name.push(format!(".{}.{}.tmp",
+                           process::id(),
+                           NEXT_TEMP_ID.fetch_add(1, Ordering::SeqCst)));
+    path.with_file_name(file_name)
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers. The last write is padded to the cluster size.
+fn write_data(file: &File, data: &[u8]) -> Result<(), String> {
+    let cluster_size = File::get_cluster_size();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let size = min(data.len() - offset, WRITE_CHUNK_SIZE);
+
+        if size % cluster_size == 0 {
+            try!(write_file_blocking(file.handle(), data[offset..].as_ptr(), size, offset as u64));
+        } else {
+            let mut buffer = data[offset..offset + size].to_vec();
+
+            buffer.resize(((size + cluster_size - 1) / cluster_size) * cluster_size, 0);
+            try!(write_file_blocking(file.handle(), buffer.as_ptr(), buffer.len(), offset as u64));
+        }
+        offset += size;
+    }
+    set_file_len(file.handle(), data.len() as u64)
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers. With MOVEFILE_WRITE_THROUGH the rename is on the disk
+// when it returns, there is no directory to flush on Windows.
+fn replace(file: File, temp_path: &Path, path: &Path) -> Result<(), String> {
+    let flush_result = flush_file_buffers(file.handle());
+
+    drop(file);
+    let result = flush_result.and_then(|_| {
+        move_file_ex(temp_path, path, MOVEFILE_REPLACE_EXISTING | MOVEFILE_WRITE_THROUGH)
+    });
+    if result.is_err() {
+        let _ = ::std::fs::remove_file(temp_path);
+    }
+    result
+}
@@ -0,0 +1,318 @@
+use tools::take_locked;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem::replace;
+use std::ptr::null;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::ONCE_INIT;
+
+// -----------------------------------------------------------------------------
+// What a submission over the limits does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackpressureMode {
+    // The callback gets a would block error right away.
+    Fail,
+    // The submitting thread waits for room. It must not be an io worker, as
+    // from a callback: the operations in flight could not complete.
+    Block,
+    // The operation is queued and started once there is room.
+    Wait,
+}
+
+// -----------------------------------------------------------------------------
+// None is no limit. An operation larger than max_bytes goes once nothing else
+// is in flight.
+#[derive(Copy, Clone, Debug)]
+pub struct InFlightLimits {
+    pub max_operations: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub mode: BackpressureMode,
+}
+
+// -----------------------------------------------------------------------------
+impl InFlightLimits {
+    // -------------------------------------------------------------------------
+    pub fn new() -> InFlightLimits {
+        InFlightLimits {
+            max_operations: None,
+            max_bytes: None,
+            mode: BackpressureMode::Wait,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+type PermitCallback = Box<Fn(Result<Permit, String>)>;
+
+// -----------------------------------------------------------------------------
+struct LimiterState {
+    limits: InFlightLimits,
+    nb_operations: usize,
+    nb_bytes: usize,
+    // Operations waiting for room, in submission order.
+    waiting: VecDeque<(usize, PermitCallback)>,
+}
+
+// -----------------------------------------------------------------------------
+impl LimiterState {
+    // -------------------------------------------------------------------------
+    fn has_room(&self, cost: usize) -> bool {
+        self.limits.max_operations.map_or(true, |max| self.nb_operations < max) &&
+        self.limits.max_bytes.map_or(true, |max| self.nb_bytes == 0 || self.nb_bytes + cost <= max)
+    }
+
+    // -------------------------------------------------------------------------
+    fn take(&mut self, cost: usize) {
+        self.nb_operations += 1;
+        self.nb_bytes += cost;
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Counts the operations of the files it is attached to, from their submission
+// to the end of their callback.
+pub struct InFlightLimiter {
+    state: Mutex<LimiterState>,
+    released: Condvar,
+}
+
+// -----------------------------------------------------------------------------
+// Owned by the callback of an operation, gives the room back once dropped with
+// it.
+pub(crate) struct Permit {
+    grants: Vec<(Arc<InFlightLimiter>, usize)>,
+}
+
+// -----------------------------------------------------------------------------
+impl Permit {
+    // -------------------------------------------------------------------------
+    fn merge(&mut self, mut other: Permit) {
+        self.grants.extend(replace(&mut other.grants, Vec::new()));
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for Permit {
+    fn drop(&mut self) {
+        for (limiter, cost) in replace(&mut self.grants, Vec::new()) {
+            InFlightLimiter::release(&limiter, cost);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl InFlightLimiter {
+    // -------------------------------------------------------------------------
+    pub fn new(limits: InFlightLimits) -> Arc<InFlightLimiter> {
+        Arc::new(InFlightLimiter {
+            state: Mutex::new(LimiterState {
+                limits: limits,
+                nb_operations: 0,
+                nb_bytes: 0,
+                waiting: VecDeque::new(),
+            }),
+            released: Condvar::new(),
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn limits(&self) -> InFlightLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    // -------------------------------------------------------------------------
+    // Applies to the waiting operations too.
+    pub fn set_limits(limiter: &Arc<InFlightLimiter>, limits: InFlightLimits) {
+        limiter.state.lock().unwrap().limits = limits;
+        InFlightLimiter::start_waiting(limiter);
+    }
+
+    // -------------------------------------------------------------------------
+    // Operations and bytes in flight.
+    pub fn in_flight(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+
+        (state.nb_operations, state.nb_bytes)
+    }
+
+    // -------------------------------------------------------------------------
+    // The callback gets the permit right away if there is room, else depending
+    // on the mode.
+    pub(crate) fn acquire(limiter: &Arc<InFlightLimiter>, cost: usize, callback: PermitCallback) {
+        {
+            let mut state = limiter.state.lock().unwrap();
+
+            if !state.waiting.is_empty() || !state.has_room(cost) {
+                match state.limits.mode {
+                    BackpressureMode::Fail => {
+                        let error = format!("Error would block: {} operations and {} bytes in flight.",
+                                            state.nb_operations,
+                                            state.nb_bytes);
+
+                        drop(state);
+                        return callback.as_ref()(Err(error));
+                    }
+                    BackpressureMode::Block => {
+                        while !state.waiting.is_empty() || !state.has_room(cost) {
+                            state = limiter.released.wait(state).unwrap();
+                        }
+                    }
+                    BackpressureMode::Wait => {
+                        state.waiting.push_back((cost, callback));
+                        return;
+                    }
+                }
+            }
+            state.take(cost);
+        }
+        callback.as_ref()(Ok(Permit { grants: vec![(limiter.clone(), cost)] }));
+    }
+
+    // -------------------------------------------------------------------------
+    fn release(limiter: &Arc<InFlightLimiter>, cost: usize) {
+        {
+            let mut state = limiter.state.lock().unwrap();
+
+            state.nb_operations -= 1;
+            state.nb_bytes -= cost;
+        }
+        InFlightLimiter::start_waiting(limiter);
+    }
+
+    // -------------------------------------------------------------------------
+    fn start_waiting(limiter: &Arc<InFlightLimiter>) {
+        loop {
+            let waiting = take_locked(&limiter.state, |state| {
+                let cost = match state.waiting.front() {
+                    Some(&(cost, _)) => cost,
+                    None => return None,
+                };
+
+                if !state.has_room(cost) {
+                    return None;
+                }
+                state.take(cost);
+                state.waiting.pop_front()
+            });
+            let (cost, callback) = match waiting {
+                Some(waiting) => waiting,
+                None => break,
+            };
+
+            callback.as_ref()(Ok(Permit { grants: vec![(limiter.clone(), cost)] }));
+        }
+        limiter.released.notify_all();
+    }
+}
+
+// -----------------------------------------------------------------------------
+static INIT_POOL_IN_FLIGHT_LIMITER: Once = ONCE_INIT;
+static mut POOL_IN_FLIGHT_LIMITER: *const Mutex<Option<Arc<InFlightLimiter>>> = null();
+
+// -----------------------------------------------------------------------------
+fn pool_in_flight_limiter() -> &'static Mutex<Option<Arc<InFlightLimiter>>> {
+    unsafe {
+        INIT_POOL_IN_FLIGHT_LIMITER.call_once(|| {
+            POOL_IN_FLIGHT_LIMITER = Box::into_raw(Box::new(Mutex::new(None)));
+        });
+        &*POOL_IN_FLIGHT_LIMITER
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Limits the operations of all the files on the io workers, on top of their own
+// limiter.
+pub fn set_pool_in_flight_limiter(limiter: Option<Arc<InFlightLimiter>>) {
+    *pool_in_flight_limiter().lock().unwrap() = limiter;
+}
+
+// -----------------------------------------------------------------------------
+// Goes through the limiter of the file, then the pool one. The start gets a
+// permit of both or the first error.
+pub(crate) fn admit(file_limiter: Option<Arc<InFlightLimiter>>, cost: usize, start: PermitCallback) {
+    let mut limiters: Vec<Arc<InFlightLimiter>> = file_limiter.into_iter().collect();
+
+    limiters.extend(pool_in_flight_limiter().lock().unwrap().clone());
+    admit_all(Arc::new(limiters), 0, cost, Permit { grants: Vec::new() }, Arc::new(start));
+}
+
+// -----------------------------------------------------------------------------
+fn admit_all(limiters: Arc<Vec<Arc<InFlightLimiter>>>,
+             index: usize,
+             cost: usize,
+             permit: Permit,
+             start: Arc<PermitCallback>) {
+    if index == limiters.len() {
+        return start.as_ref()(Ok(permit));
+    }
+
+    let next_limiters = limiters.clone();
+    let permit = RefCell::new(Some(permit));
+
+    InFlightLimiter::acquire(&limiters[index], cost, Box::new(move |result| {
+        let mut permit = permit.borrow_mut().take().expect("Permit granted twice");
+
+        match result {
+            Ok(grant) => {
+                permit.merge(grant);
+                admit_all(next_limiters.clone(), index + 1, cost, permit, start.clone())
+            }
+            // The room taken from the previous limiters is given back first.
+            Err(error) => {
+                drop(permit);
+                start.as_ref()(Err(error))
+            }
+        }
+    }));
+}
+
+// -----------------------------------------------------------------------------
+// Issue starts the write with the callback it is given, which owns the permit.
+pub(crate) fn admit_write(file_limiter: Option<Arc<InFlightLimiter>>,
+                          cost: usize,
+                          callback: Box<Fn(Result<(), String>)>,
+                          issue: Box<Fn(Box<Fn(Result<(), String>)>)>) {
+    let callback = RefCell::new(Some(callback));
+
+    admit(file_limiter, cost, Box::new(move |permit| {
+        let callback = callback.borrow_mut().take().expect("Write started twice");
+
+        match permit {
+            Ok(permit) => {
+                issue.as_ref()(Box::new(move |result| {
+                    let _permit = &permit;
+
+                    callback.as_ref()(result)
+                }))
+            }
+            Err(error) => callback.as_ref()(Err(error)),
+        }
+    }));
+}
+
+// -----------------------------------------------------------------------------
+pub(crate) fn admit_read(file_limiter: Option<Arc<InFlightLimiter>>,
+                         cost: usize,
+                         callback: Box<Fn(Result<&[u8], String>)>,
+                         issue: Box<Fn(Box<Fn(Result<&[u8], String>)>)>) {
+    let callback = RefCell::new(Some(callback));
+
+    admit(file_limiter, cost, Box::new(move |permit| {
+        let callback = callback.borrow_mut().take().expect("Read started twice");
+
+        match permit {
+            Ok(permit) => {
+                issue.as_ref()(Box::new(move |result| {
+                    let _permit = &permit;
+
+                    callback.as_ref()(result)
+                }))
+            }
+            Err(error) => callback.as_ref()(Err(error)),
+        }
+    }));
+}
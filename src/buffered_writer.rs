@@ -0,0 +1,378 @@
+use file::File;
+
+use win_api_helper::get_file_size_ex;
+use win_api_helper::read_file_blocking;
+
+use std::cmp::max;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::mem::replace;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct BufferedWriterOptions {
+    // Rounded up to the cluster size, a buffer is written once full.
+    pub buffer_size: usize,
+    // The writes block while this many buffers are being written.
+    pub max_pending_flushes: usize,
+    // The data not written yet is flushed at this interval.
+    pub flush_interval: Option<Duration>,
+}
+
+// -----------------------------------------------------------------------------
+impl BufferedWriterOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> BufferedWriterOptions {
+        BufferedWriterOptions {
+            buffer_size: 1 << 20,
+            max_pending_flushes: 4,
+            flush_interval: None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct WriterState {
+    // Data from buffer_offset, aligned to the cluster size. Its start may
+    // already be written by the last partial flush, until written_len.
+    buffer: Vec<u8>,
+    buffer_offset: u64,
+    written_len: usize,
+    // Writes in flight, start to end of their data.
+    flushing: BTreeMap<u64, u64>,
+    // A partial flush pads the last cluster: nothing else is written until
+    // the file length is set back.
+    partial_flushing: bool,
+    // The data not written yet is flushed as soon as there is room.
+    flush_requested: bool,
+    // Sticky, reported by the next write or flush.
+    error: Option<String>,
+    // Called once the data is written up to their end.
+    flush_waiters: Vec<(u64, Box<Fn(Result<(), String>)>)>,
+}
+
+// -----------------------------------------------------------------------------
+struct Writer {
+    file: File,
+    buffer_size: usize,
+    max_pending_flushes: usize,
+    state: Mutex<WriterState>,
+    flush_done: Condvar,
+    // Declared after the file: dropped once it is closed.
+    close_callback: CloseCallback,
+}
+
+// -----------------------------------------------------------------------------
+// Receives the result of the last flush, set by close.
+struct CloseCallback {
+    callback: Mutex<Option<Box<Fn(Result<(), String>)>>>,
+    result: Arc<Mutex<Result<(), String>>>,
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for CloseCallback {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.lock().unwrap().take() {
+            callback.as_ref()(self.result.lock().unwrap().clone());
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Lets the timer thread reach the writer. Writer is not Send because of the
+// HANDLE of its file and of the callbacks it holds:
+// - the HANDLE is a kernel object usable from any thread, and it is only
+//   closed once, when the last Arc is dropped, whichever thread that is.
+// - the flush and close callbacks are only touched with their mutex locked,
+//   so never by two threads at once. Like every callback of the crate they
+//   are already called from the io workers rather than from the thread which
+//   gave them: calling them from the timer thread too changes nothing.
+// - the flushes go through write_at, whose coalescer, limiters and sequencer
+//   keep their state behind a mutex.
+// The reference counts of Arc and Weak are atomic.
+struct TimerWriter(Weak<Writer>);
+
+unsafe impl Send for TimerWriter {}
+
+// -----------------------------------------------------------------------------
+// Appends to the end of a file through aligned buffers written in the
+// background. What is left is flushed when it is dropped, without waiting for
+// it: close reports the outcome. The buffers are written with write_at, the
+// in-flight limiter of the file must not use BackpressureMode::Block: the
+// next flush is started from the io worker completing the last one.
+pub struct BufferedWriter {
+    writer: Arc<Writer>,
+}
+
+// -----------------------------------------------------------------------------
+impl BufferedWriter {
+    // -------------------------------------------------------------------------
+    pub fn new(file: File, options: BufferedWriterOptions) -> Result<BufferedWriter, String> {
+        let cluster_size = File::get_cluster_size();
+        let file_size = try!(get_file_size_ex(file.handle()));
+        let buffer_offset = (file_size / cluster_size as u64) * cluster_size as u64;
+        let buffer_size = max(1, (options.buffer_size + cluster_size - 1) / cluster_size) *
+                          cluster_size;
+        let mut buffer = vec![0; cluster_size];
+
+        // The last cluster is rewritten with the new data.
+        let tail_len = (file_size - buffer_offset) as usize;
+        if tail_len > 0 {
+            try!(read_file_blocking(file.handle(), buffer.as_mut_ptr(), cluster_size, buffer_offset));
+        }
+        buffer.truncate(tail_len);
+        buffer.reserve(buffer_size - tail_len);
+
+        let writer = Arc::new(Writer {
+            file: file,
+            buffer_size: buffer_size,
+            max_pending_flushes: max(1, options.max_pending_flushes),
+            state: Mutex::new(WriterState {
+                buffer: buffer,
+                buffer_offset: buffer_offset,
+                written_len: tail_len,
+                flushing: BTreeMap::new(),
+                partial_flushing: false,
+                flush_requested: false,
+                error: None,
+                flush_waiters: Vec::new(),
+            }),
+            flush_done: Condvar::new(),
+            close_callback: CloseCallback {
+                callback: Mutex::new(None),
+                result: Arc::new(Mutex::new(Err("Error close: the data was not written.".
+                    to_string()))),
+            },
+        });
+
+        if let Some(interval) = options.flush_interval {
+            let timer_writer = TimerWriter(Arc::downgrade(&writer));
+
+            thread::spawn(move || flush_periodically(timer_writer, interval));
+        }
+        Ok(BufferedWriter { writer: writer })
+    }
+
+    // -------------------------------------------------------------------------
+    // Copies the data to the buffers. Blocks while the full buffers cannot be
+    // written because too many flushes are pending. The calling thread must not
+    // be an io worker, as from a callback: the pending flushes could not
+    // complete.
+    pub fn write(&self, mut data: &[u8]) -> Result<(), String> {
+        while !data.is_empty() {
+            let mut state = try!(self.writer.wait_for_room());
+            let size = min(data.len(), self.writer.buffer_size - state.buffer.len());
+
+            state.buffer.extend_from_slice(&data[..size]);
+            data = &data[size..];
+            if state.buffer.len() == self.writer.buffer_size {
+                Writer::start_flush(&self.writer, state);
+            }
+        }
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Writes what is buffered, the callback is called once everything written
+    // before is in the file. Does not block: the flush starts once the
+    // pending ones leave room for it.
+    pub fn flush(&self, callback: Box<Fn(Result<(), String>)>) {
+        let mut state = self.writer.state.lock().unwrap();
+        let end = state.buffer_offset + state.buffer.len() as u64;
+
+        state.flush_waiters.push((end, callback));
+        state.flush_requested = true;
+        Writer::start_requested_flush(&self.writer, state);
+    }
+
+    // -------------------------------------------------------------------------
+    // The callback is called once everything written before is in the file,
+    // without starting a flush: the full buffers, the timer or a later flush
+    // write it.
+    pub fn when_written(&self, callback: Box<Fn(Result<(), String>)>) {
+        let mut state = self.writer.state.lock().unwrap();
+        let end = state.buffer_offset + state.buffer.len() as u64;
+
+        state.flush_waiters.push((end, callback));
+        Writer::start_requested_flush(&self.writer, state);
+    }
+
+    // -------------------------------------------------------------------------
+    // Flushes what is left. The callback receives the first error of the
+    // writes, once the file is closed.
+    pub fn close(self, callback: Box<Fn(Result<(), String>)>) {
+        let result = self.writer.close_callback.result.clone();
+
+        *self.writer.close_callback.callback.lock().unwrap() = Some(callback);
+        self.flush(Box::new(move |flushed| *result.lock().unwrap() = flushed));
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for BufferedWriter {
+    fn drop(&mut self) {
+        self.flush(Box::new(|_| {}));
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Writer {
+    // -------------------------------------------------------------------------
+    fn wait_for_room(&self) -> Result<MutexGuard<WriterState>, String> {
+        let mut state = self.state.lock().unwrap();
+
+        while state.error.is_none() &&
+              (state.partial_flushing || state.flushing.len() >= self.max_pending_flushes) {
+            state = self.flush_done.wait(state).unwrap();
+        }
+        match state.error.clone() {
+            Some(error) => Err(error),
+            None => Ok(state),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Writes the buffer, padded if it is not full: it is then kept so the next
+    // data is written after it. The write is started once the state is
+    // unlocked, it can fail right away.
+    fn start_flush(writer: &Arc<Writer>, mut state: MutexGuard<WriterState>) {
+        let cluster_size = File::get_cluster_size();
+        let offset = state.buffer_offset;
+        let end = offset + state.buffer.len() as u64;
+        let partial = state.buffer.len() < writer.buffer_size;
+        let buffer = if partial {
+            let full_clusters_len = (state.buffer.len() / cluster_size) * cluster_size;
+            let mut buffer = state.buffer.clone();
+
+            buffer.resize(((buffer.len() + cluster_size - 1) / cluster_size) * cluster_size, 0);
+            state.buffer.drain(..full_clusters_len);
+            state.buffer_offset += full_clusters_len as u64;
+            state.written_len = state.buffer.len();
+            state.partial_flushing = true;
+            buffer
+        } else {
+            state.buffer_offset = end;
+            state.written_len = 0;
+            replace(&mut state.buffer, Vec::with_capacity(writer.buffer_size))
+        };
+
+        state.flushing.insert(offset, end);
+        drop(state);
+
+        let writer_flush = writer.clone();
+
+        // Through the chain of write_at: the scheduler, the limiters and the
+        // ordering of the file apply to the flushes too.
+        writer.file.write_at(offset, buffer, Box::new(move |result| {
+            let writer_len = writer_flush.clone();
+
+            match result {
+                Ok(()) if partial => {
+                    writer_flush.file.set_len(end, Box::new(move |result| {
+                        Writer::complete_flush(&writer_len, offset, partial, result);
+                    }))
+                }
+                result => Writer::complete_flush(&writer_flush, offset, partial, result),
+            }
+        }));
+    }
+
+    // -------------------------------------------------------------------------
+    fn complete_flush(writer: &Arc<Writer>,
+                      offset: u64,
+                      partial: bool,
+                      result: Result<(), String>) {
+        let mut state = writer.state.lock().unwrap();
+
+        state.flushing.remove(&offset);
+        if partial {
+            state.partial_flushing = false;
+        }
+        if let Err(error) = result {
+            if state.error.is_none() {
+                state.error = Some(error);
+            }
+        }
+        writer.flush_done.notify_all();
+        Writer::start_requested_flush(writer, state);
+    }
+
+    // -------------------------------------------------------------------------
+    // Starts the requested flush if the pending ones leave room for it, it is
+    // started again by the completion of a flush otherwise.
+    fn start_requested_flush(writer: &Arc<Writer>, mut state: MutexGuard<WriterState>) {
+        let has_room = !state.partial_flushing &&
+                       state.flushing.len() < writer.max_pending_flushes;
+        let waiters = writer.ready_waiters(&mut state);
+
+        if state.flush_requested && has_room && state.error.is_none() {
+            state.flush_requested = false;
+            if state.buffer.len() > state.written_len {
+                Writer::start_flush(writer, state);
+            } else {
+                drop(state);
+            }
+        } else {
+            drop(state);
+        }
+        call_waiters(waiters);
+    }
+
+    // -------------------------------------------------------------------------
+    // The waiters whose data is written, or all of them after an error.
+    fn ready_waiters(&self, state: &mut WriterState)
+                     -> Vec<(Box<Fn(Result<(), String>)>, Result<(), String>)> {
+        let written_end = match state.flushing.keys().next() {
+            Some(&offset) => offset,
+            None => state.buffer_offset + state.written_len as u64,
+        };
+        let error = state.error.clone();
+        let (ready, waiting): (Vec<_>, Vec<_>) = replace(&mut state.flush_waiters, Vec::new()).
+            into_iter().
+            partition(|&(end, _)| error.is_some() || end <= written_end);
+
+        state.flush_waiters = waiting;
+        ready.into_iter().map(|(_, callback)| {
+            (callback, match error {
+                Some(ref error) => Err(error.clone()),
+                None => Ok(()),
+            })
+        }).collect()
+    }
+
+    // -------------------------------------------------------------------------
+    fn flush_unwritten(writer: &Arc<Writer>) {
+        let mut state = writer.state.lock().unwrap();
+
+        if state.buffer.len() > state.written_len {
+            state.flush_requested = true;
+        }
+        Writer::start_requested_flush(writer, state);
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn call_waiters(waiters: Vec<(Box<Fn(Result<(), String>)>, Result<(), String>)>) {
+    for (callback, result) in waiters {
+        callback.as_ref()(result);
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Stops once the BufferedWriter is dropped.
+fn flush_periodically(timer_writer: TimerWriter, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match timer_writer.0.upgrade() {
+            Some(writer) => Writer::flush_unwritten(&writer),
+            None => return,
+        }
+    }
+}
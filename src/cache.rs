@@ -0,0 +1,398 @@
+use file::File;
+
+use win_api_helper::get_file_size_ex;
+
+use std::cmp::max;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+static NEXT_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct BlockCacheOptions {
+    // Rounded up to the cluster size.
+    pub block_size: usize,
+    // Memory budget of the cached blocks, split between the shards.
+    pub capacity: usize,
+    // Each shard has its own lock and LRU list.
+    pub nb_shards: usize,
+}
+
+// -----------------------------------------------------------------------------
+impl BlockCacheOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> BlockCacheOptions {
+        BlockCacheOptions {
+            block_size: 4096,
+            capacity: 64 << 20,
+            nb_shards: 16,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: usize,
+    // Blocks not in the cache, including the ones already being read for
+    // another request.
+    pub misses: usize,
+    // Reads sent to the disk, at most one per block at a time.
+    pub reads: usize,
+    pub evictions: usize,
+}
+
+// -----------------------------------------------------------------------------
+type BlockKey = (usize, u64);
+type Block = Arc<Vec<u8>>;
+type BlockCallback = Box<Fn(Result<Block, String>)>;
+
+// -----------------------------------------------------------------------------
+struct CachedBlock {
+    data: Block,
+    last_use: u64,
+}
+
+// -----------------------------------------------------------------------------
+struct Shard {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    // Least recently used first.
+    lru: BTreeMap<u64, BlockKey>,
+    // Blocks being read, with the callbacks waiting for them.
+    pending: HashMap<BlockKey, Vec<BlockCallback>>,
+    next_use: u64,
+    size: usize,
+}
+
+// -----------------------------------------------------------------------------
+// Shared by the CachedFiles: the blocks are keyed by file and block index.
+pub struct BlockCache {
+    block_size: usize,
+    shard_capacity: usize,
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    reads: AtomicUsize,
+    evictions: AtomicUsize,
+}
+
+// -----------------------------------------------------------------------------
+impl BlockCache {
+    // -------------------------------------------------------------------------
+    pub fn new(options: BlockCacheOptions) -> Arc<BlockCache> {
+        let cluster_size = File::get_cluster_size();
+        let nb_shards = max(1, options.nb_shards);
+
+        Arc::new(BlockCache {
+            block_size: max(1, (options.block_size + cluster_size - 1) / cluster_size) *
+                        cluster_size,
+            shard_capacity: options.capacity / nb_shards,
+            shards: (0..nb_shards).map(|_| {
+                Mutex::new(Shard {
+                    blocks: HashMap::new(),
+                    lru: BTreeMap::new(),
+                    pending: HashMap::new(),
+                    next_use: 0,
+                    size: 0,
+                })
+            }).collect(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            reads: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::SeqCst),
+            misses: self.misses.load(Ordering::SeqCst),
+            reads: self.reads.load(Ordering::SeqCst),
+            evictions: self.evictions.load(Ordering::SeqCst),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn shard(&self, key: &BlockKey) -> &Mutex<Shard> {
+        let hash = key.0.wrapping_mul(31).wrapping_add(key.1 as usize);
+
+        &self.shards[hash % self.shards.len()]
+    }
+
+    // -------------------------------------------------------------------------
+    // The callback is called right away on a hit, after the read otherwise.
+    // Concurrent misses on the same block share a single read, which goes
+    // through the read_at of the file.
+    fn get_block(cache: &Arc<BlockCache>,
+                 file: &File,
+                 dropped: &Arc<AtomicBool>,
+                 key: BlockKey,
+                 callback: BlockCallback) {
+        let hit = {
+            let mut shard = cache.shard(&key).lock().unwrap();
+            let shard = &mut *shard;
+            let next_use = shard.next_use;
+            let data = match shard.blocks.get_mut(&key) {
+                Some(block) => {
+                    shard.lru.remove(&block.last_use);
+                    shard.lru.insert(next_use, key);
+                    shard.next_use += 1;
+                    block.last_use = next_use;
+                    Some(block.data.clone())
+                }
+                None => None,
+            };
+
+            match data {
+                Some(data) => Some((data, callback)),
+                None => {
+                    cache.misses.fetch_add(1, Ordering::SeqCst);
+                    if let Some(waiters) = shard.pending.get_mut(&key) {
+                        waiters.push(callback);
+                        return;
+                    }
+                    shard.pending.insert(key, vec![callback]);
+                    None
+                }
+            }
+        };
+
+        if let Some((data, callback)) = hit {
+            cache.hits.fetch_add(1, Ordering::SeqCst);
+            return callback.as_ref()(Ok(data));
+        }
+
+        let cache_read = cache.clone();
+        let dropped = dropped.clone();
+
+        cache.reads.fetch_add(1, Ordering::SeqCst);
+        file.read_at(key.1 * cache.block_size as u64,
+                     cache.block_size,
+                     Box::new(move |result| {
+                         cache_read.complete_block(key, &dropped, result.map(|data| {
+                             Arc::new(data.to_vec())
+                         }));
+                     }));
+    }
+
+    // -------------------------------------------------------------------------
+    // The waiters are called without the shard locked: they can read other
+    // blocks. The block is not cached if its file was dropped during the read:
+    // remove_file already ran, nothing would remove it.
+    fn complete_block(&self, key: BlockKey, dropped: &AtomicBool, result: Result<Block, String>) {
+        let waiters = {
+            let mut shard = self.shard(&key).lock().unwrap();
+            let waiters = shard.pending.remove(&key).unwrap_or(Vec::new());
+
+            let cached = if dropped.load(Ordering::SeqCst) { None } else { result.as_ref().ok() };
+
+            if let Some(data) = cached {
+                let last_use = shard.next_use;
+
+                shard.next_use += 1;
+                shard.size += data.len();
+                shard.lru.insert(last_use, key);
+                shard.blocks.insert(key, CachedBlock { data: data.clone(), last_use: last_use });
+                self.evict(&mut shard);
+            }
+            waiters
+        };
+
+        for waiter in waiters {
+            waiter.as_ref()(result.clone());
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn evict(&self, shard: &mut Shard) {
+        while shard.size > self.shard_capacity {
+            let (last_use, key) = match shard.lru.iter().next() {
+                Some((&last_use, &key)) => (last_use, key),
+                None => return,
+            };
+
+            shard.lru.remove(&last_use);
+            if let Some(block) = shard.blocks.remove(&key) {
+                shard.size -= block.data.len();
+                self.evictions.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn remove_file(&self, file_id: usize) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<BlockKey> = shard.blocks.keys().
+                filter(|key| key.0 == file_id).
+                cloned().
+                collect();
+
+            for key in keys {
+                if let Some(block) = shard.blocks.remove(&key) {
+                    shard.lru.remove(&block.last_use);
+                    shard.size -= block.data.len();
+                }
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct CachedRead {
+    offset: u64,
+    len: usize,
+    // Offset of the first block.
+    start: u64,
+    block_size: usize,
+    state: Mutex<CachedReadState>,
+    callback: Box<Fn(Result<Vec<u8>, String>)>,
+}
+
+// -----------------------------------------------------------------------------
+struct CachedReadState {
+    blocks: Vec<Option<Block>>,
+    nb_missing: usize,
+    error: Option<String>,
+}
+
+// -----------------------------------------------------------------------------
+// Reads of a file which is not modified while it is opened go through the
+// block cache. Its blocks are removed from the cache when it is dropped.
+pub struct CachedFile {
+    file: File,
+    file_id: usize,
+    // Set before the blocks are removed, checked by the reads in flight.
+    dropped: Arc<AtomicBool>,
+    cache: Arc<BlockCache>,
+}
+
+// -----------------------------------------------------------------------------
+impl CachedFile {
+    // -------------------------------------------------------------------------
+    pub fn new(file: File, cache: Arc<BlockCache>) -> CachedFile {
+        CachedFile {
+            file: file,
+            file_id: NEXT_FILE_ID.fetch_add(1, Ordering::SeqCst),
+            dropped: Arc::new(AtomicBool::new(false)),
+            cache: cache,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    // -------------------------------------------------------------------------
+    // The data is shorter than len if the file ends before.
+    pub fn read_at(&self, offset: u64, len: usize, callback: Box<Fn(Result<Vec<u8>, String>)>) {
+        let file_size = match get_file_size_ex(self.file.handle()) {
+            Ok(file_size) => file_size,
+            Err(error) => return callback.as_ref()(Err(error)),
+        };
+        let end = min(offset.checked_add(len as u64).unwrap_or(u64::max_value()), file_size);
+
+        if end <= offset {
+            return callback.as_ref()(Ok(Vec::new()));
+        }
+
+        let len = (end - offset) as usize;
+        let block_size = self.cache.block_size as u64;
+        let first_block = offset / block_size;
+        let last_block = (end - 1) / block_size;
+        let nb_blocks = (last_block - first_block + 1) as usize;
+        let read = Arc::new(CachedRead {
+            offset: offset,
+            len: len,
+            start: first_block * block_size,
+            block_size: self.cache.block_size,
+            state: Mutex::new(CachedReadState {
+                blocks: vec![None; nb_blocks],
+                nb_missing: nb_blocks,
+                error: None,
+            }),
+            callback: callback,
+        });
+
+        for index in 0..nb_blocks {
+            let read = read.clone();
+
+            BlockCache::get_block(&self.cache,
+                                  &self.file,
+                                  &self.dropped,
+                                  (self.file_id, first_block + index as u64),
+                                  Box::new(move |result| read.complete_block(index, result)));
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for CachedFile {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::SeqCst);
+        self.cache.remove_file(self.file_id);
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl CachedRead {
+    // -------------------------------------------------------------------------
+    fn complete_block(&self, index: usize, result: Result<Block, String>) {
+        let result = {
+            let mut state = self.state.lock().unwrap();
+
+            match result {
+                Ok(block) => state.blocks[index] = Some(block),
+                Err(error) => {
+                    if state.error.is_none() {
+                        state.error = Some(error);
+                    }
+                }
+            }
+            state.nb_missing -= 1;
+            if state.nb_missing > 0 {
+                return;
+            }
+            match state.error.take() {
+                Some(error) => Err(error),
+                None => Ok(self.assemble(&state.blocks)),
+            }
+        };
+
+        self.callback.as_ref()(result);
+    }
+
+    // -------------------------------------------------------------------------
+    // A short block is the end of the file.
+    fn assemble(&self, blocks: &[Option<Block>]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.len);
+        let mut skip = (self.offset - self.start) as usize;
+
+        for block in blocks {
+            let block = block.as_ref().expect("Block missing from a completed read");
+            let start = min(skip, block.len());
+            let end = min(block.len(), start + self.len - data.len());
+
+            data.extend_from_slice(&block[start..end]);
+            skip = 0;
+            if data.len() == self.len || block.len() < self.block_size {
+                break;
+            }
+        }
+        data
+    }
+}
@@ -0,0 +1,373 @@
+use async_data::AsyncData;
+
+use file_handle::FileHandle;
+use file_handle::holding;
+use file_handle::holding_read;
+
+use tools::read_file_async_data;
+use tools::take_locked;
+use tools::write_file_async_data_buffer;
+
+use scheduler::Priority;
+use scheduler::scheduler;
+
+use rate_limit::IoKind;
+use rate_limit::RateLimiter;
+use rate_limit::acquire_rate;
+
+use std::cmp::max;
+use std::cmp::min;
+use std::cell::RefCell;
+use std::mem::replace;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct CoalesceOptions {
+    // Requests in flight for the file, the ones submitted past it are queued
+    // and merged with their neighbours.
+    pub max_in_flight: usize,
+    // Merged requests do not grow past it, unless a single one is larger.
+    pub max_request_size: usize,
+}
+
+// -----------------------------------------------------------------------------
+impl CoalesceOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> CoalesceOptions {
+        CoalesceOptions {
+            max_in_flight: 4,
+            max_request_size: 1 << 20,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Every request is sent right away.
+    pub fn disabled() -> CoalesceOptions {
+        CoalesceOptions {
+            max_in_flight: usize::max_value(),
+            max_request_size: 0,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct PendingRead {
+    priority: Priority,
+    offset: u64,
+    len: usize,
+    callback: Box<Fn(Result<&[u8], String>)>,
+}
+
+// -----------------------------------------------------------------------------
+struct PendingWrite {
+    priority: Priority,
+    offset: u64,
+    data: Vec<u8>,
+    callback: Box<Fn(Result<(), String>)>,
+}
+
+// -----------------------------------------------------------------------------
+struct CoalescerState {
+    options: CoalesceOptions,
+    nb_in_flight: usize,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    reads: Vec<PendingRead>,
+    writes: Vec<PendingWrite>,
+}
+
+// -----------------------------------------------------------------------------
+// Queue of the positional reads and writes of a file. The queued requests fail
+// once the File is dropped.
+pub(crate) struct Coalescer {
+    file: Weak<FileHandle>,
+    cluster_size: usize,
+    state: Mutex<CoalescerState>,
+}
+
+// -----------------------------------------------------------------------------
+impl Coalescer {
+    // -------------------------------------------------------------------------
+    pub(crate) fn new(file: Weak<FileHandle>, cluster_size: usize) -> Arc<Coalescer> {
+        Arc::new(Coalescer {
+            file: file,
+            cluster_size: cluster_size,
+            state: Mutex::new(CoalescerState {
+                options: CoalesceOptions::new(),
+                nb_in_flight: 0,
+                rate_limiter: None,
+                reads: Vec::new(),
+                writes: Vec::new(),
+            }),
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn set_options(coalescer: &Arc<Coalescer>, options: CoalesceOptions) {
+        coalescer.state.lock().unwrap().options = options;
+        schedule(coalescer);
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn set_rate_limiter(&self, rate_limiter: Option<Arc<RateLimiter>>) {
+        self.state.lock().unwrap().rate_limiter = rate_limiter;
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn read_at(coalescer: &Arc<Coalescer>,
+                          priority: Priority,
+                          offset: u64,
+                          len: usize,
+                          callback: Box<Fn(Result<&[u8], String>)>) {
+        coalescer.state.lock().unwrap().reads.push(PendingRead {
+            priority: priority,
+            offset: offset,
+            len: len,
+            callback: callback,
+        });
+        schedule(coalescer);
+    }
+
+    // -------------------------------------------------------------------------
+    // The offset and the size of the data must be multiples of the cluster
+    // size.
+    pub(crate) fn write_at(coalescer: &Arc<Coalescer>,
+                           priority: Priority,
+                           offset: u64,
+                           data: Vec<u8>,
+                           callback: Box<Fn(Result<(), String>)>) {
+        let cluster_size = coalescer.cluster_size;
+
+        if offset % cluster_size as u64 != 0 || data.len() % cluster_size != 0 {
+            return callback.as_ref()(Err(format!(
+                "Error write_at: offset and size must be multiples of {}.", cluster_size)));
+        }
+        coalescer.state.lock().unwrap().writes.push(PendingWrite {
+            priority: priority,
+            offset: offset,
+            data: data,
+            callback: callback,
+        });
+        schedule(coalescer);
+    }
+
+    // -------------------------------------------------------------------------
+    fn round_down(&self, offset: u64) -> u64 {
+        (offset / self.cluster_size as u64) * self.cluster_size as u64
+    }
+
+    // -------------------------------------------------------------------------
+    fn round_up(&self, offset: u64) -> u64 {
+        self.round_down(offset + self.cluster_size as u64 - 1)
+    }
+
+    // -------------------------------------------------------------------------
+    // Sorted by priority and offset, each group is a range of overlapping or
+    // adjacent reads of the same priority once aligned.
+    fn group_reads(&self, mut reads: Vec<PendingRead>, max_size: u64) -> Vec<Vec<PendingRead>> {
+        let mut groups: Vec<(u64, u64, Vec<PendingRead>)> = Vec::new();
+
+        reads.sort_by_key(|read| (read.priority, read.offset));
+        for read in reads {
+            let start = self.round_down(read.offset);
+            let end = max(self.round_up(read.offset + read.len as u64),
+                          start + self.cluster_size as u64);
+            let merged = match groups.last_mut() {
+                Some(&mut (group_start, ref mut group_end, ref mut group))
+                    if group[0].priority == read.priority && start <= *group_end &&
+                       max(end, *group_end) - group_start <= max_size => {
+                    *group_end = max(end, *group_end);
+                    group.push(read);
+                    None
+                }
+                _ => Some(read),
+            };
+
+            if let Some(read) = merged {
+                groups.push((start, end, vec![read]));
+            }
+        }
+        groups.into_iter().map(|(_, _, group)| group).collect()
+    }
+
+    // -------------------------------------------------------------------------
+    // Sorted by priority and offset, each group is a run of writes of the same
+    // priority following each other.
+    fn group_writes(&self, mut writes: Vec<PendingWrite>, max_size: u64) -> Vec<Vec<PendingWrite>> {
+        let mut groups: Vec<(u64, u64, Vec<PendingWrite>)> = Vec::new();
+
+        writes.sort_by_key(|write| (write.priority, write.offset));
+        for write in writes {
+            let start = write.offset;
+            let end = start + write.data.len() as u64;
+            let merged = match groups.last_mut() {
+                Some(&mut (group_start, ref mut group_end, ref mut group))
+                    if group[0].priority == write.priority && start == *group_end &&
+                       end - group_start <= max_size => {
+                    *group_end = end;
+                    group.push(write);
+                    None
+                }
+                _ => Some(write),
+            };
+
+            if let Some(write) = merged {
+                groups.push((start, end, vec![write]));
+            }
+        }
+        groups.into_iter().map(|(_, _, group)| group).collect()
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn schedule(coalescer: &Arc<Coalescer>) {
+    let (read_groups, write_groups) = take_locked(&coalescer.state, |state| {
+        let mut read_groups = Vec::new();
+        let mut write_groups = Vec::new();
+        let max_size = state.options.max_request_size as u64;
+
+        if state.nb_in_flight >= state.options.max_in_flight {
+            return (read_groups, write_groups);
+        }
+
+        let mut groups = coalescer.group_reads(replace(&mut state.reads, Vec::new()), max_size);
+        while !groups.is_empty() && state.nb_in_flight < state.options.max_in_flight {
+            read_groups.push(groups.remove(0));
+            state.nb_in_flight += 1;
+        }
+        state.reads = groups.into_iter().flat_map(|group| group).collect();
+
+        let mut groups = coalescer.group_writes(replace(&mut state.writes, Vec::new()), max_size);
+        while !groups.is_empty() && state.nb_in_flight < state.options.max_in_flight {
+            write_groups.push(groups.remove(0));
+            state.nb_in_flight += 1;
+        }
+        state.writes = groups.into_iter().flat_map(|group| group).collect();
+        (read_groups, write_groups)
+    });
+
+    for group in read_groups {
+        send_reads(coalescer, group);
+    }
+    for group in write_groups {
+        send_writes(coalescer, group);
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn complete(coalescer: &Arc<Coalescer>) {
+    coalescer.state.lock().unwrap().nb_in_flight -= 1;
+    schedule(coalescer);
+}
+
+// -----------------------------------------------------------------------------
+// One aligned read covering the group, split back to each read. The reads
+// past the end of the file get less data.
+fn send_reads(coalescer: &Arc<Coalescer>, group: Vec<PendingRead>) {
+    let priority = group[0].priority;
+    let start = coalescer.round_down(group[0].offset);
+    let end = group.iter().
+        map(|read| coalescer.round_up(read.offset + read.len as u64)).
+        fold(start + coalescer.cluster_size as u64, max);
+    let coalescer_read = coalescer.clone();
+    let callback: Box<Fn(Result<&[u8], String>)> = Box::new(move |result| {
+        for read in &group {
+            let read_start = min((read.offset - start) as usize,
+                                 result.as_ref().map_or(0, |data| data.len()));
+
+            read.callback.as_ref()(result.clone().map(|data| {
+                &data[read_start..min(read_start + read.len, data.len())]
+            }));
+        }
+        scheduler().complete(priority);
+        complete(&coalescer_read);
+    });
+    let file = coalescer.file.clone();
+    let callback = RefCell::new(Some(callback));
+
+    submit(coalescer, priority, IoKind::Read, (end - start) as usize, Box::new(move || {
+        let callback = callback.borrow_mut().take().expect("Read started twice");
+
+        match FileHandle::start(&file) {
+            Ok(handle) => {
+                let file = handle.raw();
+                let callback = holding_read(vec![handle], callback);
+
+                read_file_async_data(file, Box::new(AsyncData::new_read_at_data(
+                    file,
+                    start,
+                    (end - start) as usize,
+                    callback)));
+            }
+            Err(error) => callback.as_ref()(Err(error)),
+        }
+    }));
+}
+
+// -----------------------------------------------------------------------------
+// The data of the group is written with a single write.
+fn send_writes(coalescer: &Arc<Coalescer>, mut group: Vec<PendingWrite>) {
+    let priority = group[0].priority;
+    let offset = group[0].offset;
+    let buffer = if group.len() == 1 {
+        replace(&mut group[0].data, Vec::new())
+    } else {
+        let mut buffer = Vec::with_capacity(group.iter().map(|write| write.data.len()).sum());
+
+        for write in &mut group {
+            buffer.extend_from_slice(&replace(&mut write.data, Vec::new()));
+        }
+        buffer
+    };
+    let buffer_ptr = buffer.as_ptr();
+    let buffer_len = buffer.len();
+    let coalescer_write = coalescer.clone();
+    let callback: Box<Fn(Result<usize, String>)> = Box::new(move |result| {
+        let result = result.and_then(|nb_bytes_written| if nb_bytes_written < buffer.len() {
+            Err(format!("Error short write: {} bytes written instead of {}.",
+                        nb_bytes_written,
+                        buffer.len()))
+        } else {
+            Ok(())
+        });
+
+        for write in &group {
+            write.callback.as_ref()(result.clone());
+        }
+        scheduler().complete(priority);
+        complete(&coalescer_write);
+    });
+    let file = coalescer.file.clone();
+    let callback = RefCell::new(Some(callback));
+
+    // The buffer is owned by the callback of the write.
+    submit(coalescer, priority, IoKind::Write, buffer_len, Box::new(move || {
+        let callback = callback.borrow_mut().take().expect("Write started twice");
+
+        match FileHandle::start(&file) {
+            Ok(handle) => {
+                let file = handle.raw();
+                let callback = holding(vec![handle], callback);
+                let async_data = Box::new(AsyncData::new_write_from_data(file, offset, callback));
+
+                write_file_async_data_buffer(file, buffer_ptr, buffer_len, async_data);
+            }
+            Err(error) => callback.as_ref()(Err(error)),
+        }
+    }));
+}
+
+// -----------------------------------------------------------------------------
+// The request waits for the rate limiters, then for the scheduler.
+fn submit(coalescer: &Coalescer, priority: Priority, kind: IoKind, cost: usize, start: Box<Fn()>) {
+    let rate_limiter = coalescer.state.lock().unwrap().rate_limiter.clone();
+    let start = RefCell::new(Some(start));
+
+    acquire_rate(rate_limiter, kind, cost, Box::new(move || {
+        let start = start.borrow_mut().take().expect("Request started twice");
+
+        scheduler().submit(priority, cost, start);
+    }));
+}
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Condvar;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use win_api_helper::create_event;
+use win_api_helper::set_event;
+use win_api_helper::reset_event;
+use win_api_helper::close_handle;
+use win_api_helper::WinHandle;
+
+use metadata::Metadata;
+
+use winapi::HANDLE;
+
+// -----------------------------------------------------------------------------
+pub type CompletionToken = usize;
+
+// -----------------------------------------------------------------------------
+// Write is also the completion of set_len, Transferred the number of bytes
+// copied or sent.
+pub enum CompletionData {
+    Read(Vec<u8>),
+    Write,
+    Metadata(Metadata),
+    Transferred(u64),
+}
+
+// -----------------------------------------------------------------------------
+pub struct Completion {
+    pub token: CompletionToken,
+    pub result: Result<CompletionData, String>,
+}
+
+// -----------------------------------------------------------------------------
+struct Queue {
+    completions: Mutex<VecDeque<Completion>>,
+    cond_var: Condvar,
+    next_token: AtomicUsize,
+    event: Option<WinHandle>,
+}
+
+// -----------------------------------------------------------------------------
+impl Queue {
+    // -------------------------------------------------------------------------
+    // Must be called with the completions locked, so the event always matches
+    // the content of the queue.
+    fn update_event(&self, completions: &VecDeque<Completion>) {
+        if let Some(event) = self.event {
+            if completions.is_empty() {
+                reset_event(event.handle).expect("Cannot reset the completion queue event");
+            } else {
+                set_event(event.handle).expect("Cannot set the completion queue event");
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for Queue {
+    fn drop(&mut self) {
+        if let Some(event) = self.event {
+            close_handle(event.handle).expect("Cannot close the completion queue event");
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Receives the completions of the operations submitted with it, instead of
+// running callbacks on the io workers. Clones share the same queue.
+#[derive(Clone)]
+pub struct CompletionQueue {
+    queue: Arc<Queue>,
+}
+
+// -----------------------------------------------------------------------------
+impl CompletionQueue {
+    // -------------------------------------------------------------------------
+    pub fn new() -> CompletionQueue {
+        CompletionQueue::with_optional_event(None)
+    }
+
+    // -------------------------------------------------------------------------
+    // The queue also owns a manual-reset event which is signaled as long as
+    // completions are available, so it can be registered in another event loop.
+    pub fn with_event() -> Result<CompletionQueue, String> {
+        let event = try!(create_event(true));
+
+        Ok(CompletionQueue::with_optional_event(Some(WinHandle { handle: event })))
+    }
+
+    // -------------------------------------------------------------------------
+    fn with_optional_event(event: Option<WinHandle>) -> CompletionQueue {
+        CompletionQueue {
+            queue: Arc::new(Queue {
+                completions: Mutex::new(VecDeque::new()),
+                cond_var: Condvar::new(),
+                next_token: AtomicUsize::new(0),
+                event: event,
+            }),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn event_handle(&self) -> Option<HANDLE> {
+        self.queue.event.map(|event| event.handle)
+    }
+
+    // -------------------------------------------------------------------------
+    // Takes all the completions available without waiting.
+    pub fn poll(&self) -> Vec<Completion> {
+        let mut completions = self.queue.completions.lock().unwrap();
+        let result = completions.drain(..).collect();
+
+        self.queue.update_event(&completions);
+        result
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn try_recv(&self) -> Option<Completion> {
+        let mut completions = self.queue.completions.lock().unwrap();
+        let completion = completions.pop_front();
+
+        self.queue.update_event(&completions);
+        completion
+    }
+
+    // -------------------------------------------------------------------------
+    // Returns None if no completion arrived before the timeout. A timeout too
+    // large for a deadline waits without one.
+    pub fn wait(&self, timeout: Duration) -> Option<Completion> {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut completions = self.queue.completions.lock().unwrap();
+
+        loop {
+            if let Some(completion) = completions.pop_front() {
+                self.queue.update_event(&completions);
+                return Some(completion);
+            }
+
+            completions = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.queue.cond_var.wait_timeout(completions, deadline - now).unwrap().0
+                }
+                None => self.queue.cond_var.wait(completions).unwrap(),
+            };
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn next_token(&self) -> CompletionToken {
+        self.queue.next_token.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn push(&self, completion: Completion) {
+        let mut completions = self.queue.completions.lock().unwrap();
+
+        completions.push_back(completion);
+        self.queue.update_event(&completions);
+        self.queue.cond_var.notify_one();
+    }
+
+    // -------------------------------------------------------------------------
+    // A callback pushing the result of an operation under a new token.
+    pub(crate) fn callback<T: 'static>(&self,
+                                       data: fn(T) -> CompletionData)
+                                       -> (CompletionToken, Box<Fn(Result<T, String>)>) {
+        let token = self.next_token();
+        let queue = self.clone();
+
+        (token, Box::new(move |result: Result<T, String>| {
+            queue.push(Completion {
+                token: token,
+                result: result.map(data),
+            });
+        }))
+    }
+
+    // -------------------------------------------------------------------------
+    // Same as callback, for the operations which read data.
+    pub(crate) fn read_callback(&self)
+                                -> (CompletionToken, Box<Fn(Result<&[u8], String>)>) {
+        let token = self.next_token();
+        let queue = self.clone();
+
+        (token, Box::new(move |result: Result<&[u8], String>| {
+            queue.push(Completion {
+                token: token,
+                result: result.map(|data| CompletionData::Read(data.to_vec())),
+            });
+        }))
+    }
+}
@@ -0,0 +1,334 @@
+use async_data::AsyncData;
+
+use tools::post_task;
+use tools::read_file_async_data_buffer;
+use tools::take_locked;
+use tools::write_file_async_data_buffer;
+
+use file::set_file_len;
+
+use rate_limit::IoKind;
+
+use scheduler::Throttle;
+
+use win_api_helper::copy_file_ex;
+use win_api_helper::device_io_control;
+use win_api_helper::get_file_size_ex;
+
+use winapi::HANDLE;
+use winapi::DWORD;
+use winapi::LPVOID;
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::mem::size_of;
+use std::cmp::min;
+use std::cmp::max;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Block cloning, only supported by ReFS.
+const FSCTL_DUPLICATE_EXTENTS_TO_FILE: DWORD = 0x00098344;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+// Chunks copied at the same time when the blocks cannot be cloned.
+const NB_CHUNKS_IN_FLIGHT: usize = 4;
+
+// -----------------------------------------------------------------------------
+#[repr(C)]
+struct DuplicateExtentsData {
+    file_handle: HANDLE,
+    source_file_offset: i64,
+    target_file_offset: i64,
+    byte_count: i64,
+}
+
+// -----------------------------------------------------------------------------
+// Copies a whole file on an io worker, letting the system offload the copy to
+// the server or the file system when it can. The progress receives the bytes
+// copied and the total.
+pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(src_path: P,
+                                            dst_path: Q,
+                                            progress: Box<Fn(u64, u64)>,
+                                            callback: Box<Fn(Result<(), String>)>) {
+    let src_path = src_path.as_ref().to_path_buf();
+    let dst_path = dst_path.as_ref().to_path_buf();
+
+    post_task(Box::new(move |result| {
+        callback.as_ref()(result.and_then(|_| {
+            copy_file_ex(&src_path, &dst_path, progress.as_ref())
+        }));
+    }));
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers. The callback receives the number of bytes copied, less
+// than len if the source ends before. When the blocks cannot be cloned, the
+// chunks are read through the throttle of the source and written through the
+// one of the destination. A clone moves no data and is not throttled.
+pub(crate) fn copy_range(src: HANDLE,
+                         dst: HANDLE,
+                         src_offset: u64,
+                         dst_offset: u64,
+                         len: u64,
+                         cluster_size: usize,
+                         throttles: (Throttle, Throttle),
+                         progress: Box<Fn(u64)>,
+                         callback: Box<Fn(Result<u64, String>)>) {
+    let prepared = prepare_copy_range(src, dst, src_offset, dst_offset, len, cluster_size);
+    let (len, dst_size) = match prepared {
+        Ok(prepared) => prepared,
+        Err(error) => return callback.as_ref()(Err(error)),
+    };
+
+    if len == 0 {
+        return callback.as_ref()(Ok(0));
+    }
+    if duplicate_extents(src, dst, src_offset, dst_offset, len).is_ok() {
+        progress.as_ref()(len);
+        return callback.as_ref()(Ok(len));
+    }
+
+    let chunked_copy = Arc::new(ChunkedCopy {
+        src: src,
+        dst: dst,
+        src_offset: src_offset,
+        dst_offset: dst_offset,
+        len: len,
+        dst_len: max(dst_size, dst_offset + len),
+        cluster_size: cluster_size,
+        throttles: throttles,
+        state: Mutex::new(ChunkedCopyState {
+            next_offset: 0,
+            nb_copying: 0,
+            copied: 0,
+            error: None,
+            done: false,
+        }),
+        progress: progress,
+        callback: callback,
+    });
+
+    schedule(&chunked_copy);
+}
+
+// -----------------------------------------------------------------------------
+// Checks the range and extends the destination to its end. Returns the length
+// to copy and the destination size before the copy.
+fn prepare_copy_range(src: HANDLE,
+                      dst: HANDLE,
+                      src_offset: u64,
+                      dst_offset: u64,
+                      len: u64,
+                      cluster_size: usize)
+                      -> Result<(u64, u64), String> {
+    let cluster_size = cluster_size as u64;
+
+    if src_offset % cluster_size != 0 || dst_offset % cluster_size != 0 {
+        return Err(format!("Error copy_range: offsets must be multiples of {}.", cluster_size));
+    }
+
+    let src_size = try!(get_file_size_ex(src));
+    let dst_size = try!(get_file_size_ex(dst));
+    let len = min(len, src_size.saturating_sub(src_offset));
+    let dst_end = dst_offset + len;
+
+    // The last unbuffered write is rounded up to the cluster size, which is
+    // only harmless at the end of the destination.
+    if len % cluster_size != 0 && dst_end < dst_size {
+        return Err(format!("Error copy_range: length must be a multiple of {} \
+                            inside the destination.",
+                           cluster_size));
+    }
+    if len > 0 && dst_end > dst_size {
+        try!(set_file_len(dst, dst_end));
+    }
+    Ok((len, dst_size))
+}
+
+// -----------------------------------------------------------------------------
+fn duplicate_extents(src: HANDLE,
+                     dst: HANDLE,
+                     src_offset: u64,
+                     dst_offset: u64,
+                     len: u64)
+                     -> Result<(), String> {
+    let mut duplicate_extents_data = DuplicateExtentsData {
+        file_handle: src,
+        source_file_offset: src_offset as i64,
+        target_file_offset: dst_offset as i64,
+        byte_count: len as i64,
+    };
+
+    device_io_control(dst,
+                      FSCTL_DUPLICATE_EXTENTS_TO_FILE,
+                      &mut duplicate_extents_data as *mut DuplicateExtentsData as LPVOID,
+                      size_of::<DuplicateExtentsData>(),
+                      null_mut(),
+                      0).map(|_| ())
+}
+
+// -----------------------------------------------------------------------------
+struct ChunkedCopyState {
+    next_offset: u64,
+    nb_copying: usize,
+    copied: u64,
+    error: Option<String>,
+    done: bool,
+}
+
+// -----------------------------------------------------------------------------
+struct ChunkedCopy {
+    src: HANDLE,
+    dst: HANDLE,
+    src_offset: u64,
+    dst_offset: u64,
+    len: u64,
+    // The last write is rounded up to the cluster size, the destination is
+    // cut back to this length once the copy is done.
+    dst_len: u64,
+    cluster_size: usize,
+    // Of the source and of the destination.
+    throttles: (Throttle, Throttle),
+    state: Mutex<ChunkedCopyState>,
+    progress: Box<Fn(u64)>,
+    callback: Box<Fn(Result<u64, String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// When the blocks cannot be cloned, several chunks are copied at the same
+// time, each read into its own buffer then written from it.
+fn schedule(chunked_copy: &Arc<ChunkedCopy>) {
+    let (chunks, result) = take_locked(&chunked_copy.state, |state| {
+        let mut chunks = Vec::new();
+
+        while state.error.is_none() && state.nb_copying < NB_CHUNKS_IN_FLIGHT &&
+              state.next_offset < chunked_copy.len {
+            let offset = state.next_offset;
+            let size = min(chunked_copy.len - offset, CHUNK_SIZE as u64) as usize;
+
+            chunks.push((offset, size));
+            state.next_offset += size as u64;
+            state.nb_copying += 1;
+        }
+        if state.nb_copying == 0 && !state.done {
+            state.done = true;
+            (chunks, Some(state.error.take()))
+        } else {
+            (chunks, None)
+        }
+    });
+
+    for (offset, size) in chunks {
+        read_chunk(chunked_copy, offset, size);
+    }
+    match result {
+        Some(Some(error)) => (chunked_copy.callback)(Err(error)),
+        Some(None) => {
+            (chunked_copy.callback)(set_file_len(chunked_copy.dst, chunked_copy.dst_len).
+                map(|_| chunked_copy.len))
+        }
+        None => {}
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn read_chunk(chunked_copy: &Arc<ChunkedCopy>, offset: u64, size: usize) {
+    let chunked_copy_chunk = chunked_copy.clone();
+
+    chunked_copy.throttles.0.submit(IoKind::Read, size, Box::new(move |request| {
+        let mut buffer = vec![0u8; chunked_copy_chunk.round_up(size)];
+        let buffer_ptr = buffer.as_mut_ptr();
+        let buffer_size = buffer.len();
+        // The buffer does not move when it is handed over to the write.
+        let buffer = RefCell::new(Some(buffer));
+        let request = RefCell::new(Some(request));
+        let chunked_copy = chunked_copy_chunk.clone();
+        let async_data = Box::new(AsyncData::new_read_into_data(
+            chunked_copy.src,
+            chunked_copy.src_offset + offset,
+            Box::new(move |result| {
+                let buffer = buffer.borrow_mut().take().expect("chunk read completed twice");
+
+                drop(request.borrow_mut().take());
+                match result {
+                    Ok(nb_bytes_read) if nb_bytes_read >= size => {
+                        write_chunk(&chunked_copy, offset, size, buffer)
+                    }
+                    result => {
+                        chunked_copy.complete_chunk(size, result.and_then(|_| {
+                            Err("Error copy_range: the source was truncated during the copy.".
+                                to_string())
+                        }));
+                        schedule(&chunked_copy);
+                    }
+                }
+            })));
+
+        read_file_async_data_buffer(chunked_copy_chunk.src, buffer_ptr, buffer_size, async_data);
+    }));
+}
+
+// -----------------------------------------------------------------------------
+fn write_chunk(chunked_copy: &Arc<ChunkedCopy>, offset: u64, size: usize, buffer: Vec<u8>) {
+    let buffer_ptr = buffer.as_ptr();
+    let buffer_size = buffer.len();
+    let buffer = RefCell::new(Some(buffer));
+    let chunked_copy_chunk = chunked_copy.clone();
+
+    chunked_copy.throttles.1.submit(IoKind::Write, size, Box::new(move |request| {
+        let buffer = buffer.borrow_mut().take().expect("chunk write started twice");
+        let chunked_copy = chunked_copy_chunk.clone();
+        let async_data = Box::new(AsyncData::new_write_from_data(
+            chunked_copy.dst,
+            chunked_copy.dst_offset + offset,
+            Box::new(move |result| {
+                // Owns the buffer until the write is done.
+                let _buffer = &buffer;
+                let _request = &request;
+
+                chunked_copy.complete_chunk(size, result.and_then(|nb_bytes_written| {
+                    if nb_bytes_written < buffer_size {
+                        Err(format!("Error short write: {} bytes written instead of {}.",
+                                    nb_bytes_written,
+                                    buffer_size))
+                    } else {
+                        Ok(nb_bytes_written)
+                    }
+                }));
+                schedule(&chunked_copy);
+            })));
+
+        write_file_async_data_buffer(chunked_copy_chunk.dst, buffer_ptr, buffer_size, async_data);
+    }));
+}
+
+// -----------------------------------------------------------------------------
+impl ChunkedCopy {
+    // -------------------------------------------------------------------------
+    fn round_up(&self, size: usize) -> usize {
+        ((size + self.cluster_size - 1) / self.cluster_size) * self.cluster_size
+    }
+
+    // -------------------------------------------------------------------------
+    // The progress is reported under the lock so that it only grows, even
+    // when the chunks complete out of order on several workers.
+    fn complete_chunk(&self, size: usize, result: Result<usize, String>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.nb_copying -= 1;
+        match result {
+            Ok(_) if state.error.is_none() => {
+                state.copied += size as u64;
+                (self.progress)(state.copied);
+            }
+            Ok(_) => {}
+            Err(error) => {
+                if state.error.is_none() {
+                    state.error = Some(error);
+                }
+            }
+        }
+    }
+}
@@ -2,68 +2,257 @@ use std::path::Path;
 
 use async_data::AsyncData;
 
+use file_handle::FileHandle;
+use file_handle::holding;
+use file_handle::with_handle;
+use file_handle::with_handle_read;
+
 use win_api_helper::create_file_async;
 use win_api_helper::create_io_completion_port;
 
 use tools::write_file_async_data;
 use tools::read_file_async_data;
+use tools::post_task;
+use tools::lock_file_async_data;
+
+use metadata::Metadata;
+use metadata::query_metadata;
+
+use sparse::Extent;
+use sparse::set_sparse;
+use sparse::set_zero_data;
+use sparse::query_allocated_extents;
+
+use copy::copy_range;
+
+use read_ahead::ReadAhead;
+use read_ahead::ReadAheadOptions;
+use read_ahead::read_all_ahead;
+
+use parallel_read::ParallelReadOptions;
+use parallel_read::read_all_parallel;
+
+use striped_write::StripedWriteOptions;
+use striped_write::write_all_striped;
+
+use coalesce::CoalesceOptions;
+use coalesce::Coalescer;
+
+use scheduler::Priority;
+use scheduler::Throttle;
+
+use rate_limit::IoKind;
+use rate_limit::RateLimiter;
+
+use backpressure::InFlightLimiter;
+use backpressure::admit_read;
+use backpressure::admit_write;
+
+use ordering::Access;
+use ordering::Sequencer;
+use ordering::ordered_read;
+use ordering::ordered_write;
+
+use win_api_helper::get_file_size_ex;
+use win_api_helper::get_file_information_by_handle_ex;
+use win_api_helper::set_file_information_by_handle;
+use win_api_helper::transmit_file;
+use win_api_helper::flush_file_buffers;
+use win_api_helper::lock_file_ex;
+use win_api_helper::unlock_file_ex;
+use win_api_helper::set_file_rename_info;
+
+
+use atomic::temp_path_for;
 
 use io_worker::init_static_completion_port_once;
 
+use completion_queue::CompletionQueue;
+use completion_queue::CompletionToken;
+use completion_queue::CompletionData;
+
 use winapi::HANDLE;
 use winapi::GENERIC_WRITE;
 use winapi::GENERIC_READ;
 use winapi::OPEN_EXISTING;
 use winapi::CREATE_NEW;
 use winapi::DWORD;
-use kernel32::CloseHandle;
+use winapi::SOCKET;
+use winapi::FILE_SHARE_READ;
+use winapi::FILE_SHARE_WRITE;
+use winapi::LOCKFILE_EXCLUSIVE_LOCK;
+use winapi::LOCKFILE_FAIL_IMMEDIATELY;
+use winapi::DELETE;
+use winapi::TRUE;
+use winapi::FALSE;
+use winapi::FILE_DISPOSITION_INFO;
+use winapi::FileDispositionInfo;
+use winapi::FILE_END_OF_FILE_INFO;
+use winapi::FILE_ALLOCATION_INFO;
+use winapi::FILE_STANDARD_INFO;
+use winapi::FileEndOfFileInfo;
+use winapi::FileAllocationInfo;
+use winapi::FileStandardInfo;
+use winapi::FILE_IO_PRIORITY_HINT_INFO;
+use winapi::FileIoPriorityHintInfo;
+use winapi::IoPriorityHintLow;
+use winapi::IoPriorityHintNormal;
+
+use std::os::windows::io::AsRawSocket;
+use std::cmp::max;
+use std::cmp::min;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::Weak;
+
+const TEMPFILE_ATTEMPTS: usize = 16;
+// Bytes sent by each TransmitFile call of send_to, rounded down to the
+// cluster size.
+const SEND_CHUNK_SIZE: u64 = 1 << 20;
 
 // -----------------------------------------------------------------------------
 pub struct File {
-    file: HANDLE,
+    file: Arc<FileHandle>,
     cluster_size: usize,
+    read_ahead: Option<Arc<ReadAhead>>,
+    parallel_read: ParallelReadOptions,
+    striped_write: StripedWriteOptions,
+    coalescer: Arc<Coalescer>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    in_flight_limiter: Option<Arc<InFlightLimiter>>,
+    sequencer: Option<Arc<Sequencer>>,
+    priority: Priority,
 }
 
 // -----------------------------------------------------------------------------
 impl File {
     // -------------------------------------------------------------------------
     pub fn create<P: AsRef<Path>>(path: P) -> Result<File, String> {
-        File::generic_create(path, CREATE_NEW)
+        File::generic_create(path, GENERIC_WRITE | GENERIC_READ, 0, CREATE_NEW)
     }
 
     // -------------------------------------------------------------------------
     pub fn open<P: AsRef<Path>>(path: P) -> Result<File, String> {
-        File::generic_create(path, OPEN_EXISTING)
+        File::generic_create(path, GENERIC_WRITE | GENERIC_READ, 0, OPEN_EXISTING)
+    }
+
+    // -------------------------------------------------------------------------
+    // Unlike open, other handles can open the file at the same time: the
+    // access is then coordinated with the lock methods.
+    pub fn open_shared<P: AsRef<Path>>(path: P) -> Result<File, String> {
+        File::generic_create(path,
+                             GENERIC_WRITE | GENERIC_READ,
+                             FILE_SHARE_READ | FILE_SHARE_WRITE,
+                             OPEN_EXISTING)
+    }
+
+    // -------------------------------------------------------------------------
+    // Creates a file with a unique name in dir, deleted when the File is
+    // dropped unless it is persisted. Windows has no unnamed files: the name
+    // is visible in dir but the file cannot be opened by anyone else.
+    pub fn tempfile<P: AsRef<Path>>(dir: P) -> Result<File, String> {
+        let mut attempts = 0;
+
+        loop {
+            let path = temp_path_for(&dir.as_ref().join("tempfile"));
+
+            match File::generic_create(&path,
+                                       GENERIC_WRITE | GENERIC_READ | DELETE,
+                                       0,
+                                       CREATE_NEW) {
+                Ok(file) => {
+                    try!(set_delete_on_close(file.handle(), true));
+                    return Ok(file);
+                }
+                // A file left by a previous process with the same id.
+                Err(_) if path.exists() && attempts < TEMPFILE_ATTEMPTS => attempts += 1,
+                Err(error) => return Err(error),
+            }
+        }
     }
 
     // -------------------------------------------------------------------------
-    fn generic_create<P: AsRef<Path>>(path: P, flags: DWORD) -> Result<File, String> {
-        let file = try!(create_file_async(path, GENERIC_WRITE | GENERIC_READ, flags));
+    fn generic_create<P: AsRef<Path>>(path: P,
+                                      desired_access: DWORD,
+                                      share_mode: DWORD,
+                                      flags: DWORD)
+                                      -> Result<File, String> {
+        let file = FileHandle::new(try!(create_file_async(path, desired_access, share_mode, flags)));
         let io_completion_port = try!(init_static_completion_port_once());
 		
-        try!(create_io_completion_port(file, io_completion_port, 0, 0));
+        try!(create_io_completion_port(file.raw(), io_completion_port, 0, 0));
         Ok(File {
+            coalescer: Coalescer::new(Arc::downgrade(&file), File::get_cluster_size()),
             file: file,
-            cluster_size: File::get_cluster_size()})
+            cluster_size: File::get_cluster_size(),
+            read_ahead: None,
+            parallel_read: ParallelReadOptions::new(),
+            striped_write: StripedWriteOptions::new(),
+            rate_limiter: None,
+            in_flight_limiter: None,
+            sequencer: None,
+            priority: Priority::Normal})
     }
     
+    // -----------------------------------------------------------------------------
+    // The completion port serviced by the io workers. It can be inspected by
+    // other event loops but packets must not be dequeued from it: use a
+    // CompletionQueue::with_event to be woken up by completions instead.
+    pub fn completion_port() -> Result<HANDLE, &'static str> {
+        init_static_completion_port_once()
+    }
+
+    // -----------------------------------------------------------------------------
+    pub(crate) fn handle(&self) -> HANDLE {
+        self.file.raw()
+    }
+
+    // -----------------------------------------------------------------------------
+    fn throttle(&self) -> Throttle {
+        Throttle {
+            priority: self.priority,
+            rate_limiter: self.rate_limiter.clone(),
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    // Held by the operations waiting to start.
+    fn weak_handle(&self) -> Weak<FileHandle> {
+        Arc::downgrade(&self.file)
+    }
+
     // -----------------------------------------------------------------------------
     pub fn get_cluster_size() -> usize {
     	1024	
     }
     
+    // -----------------------------------------------------------------------------
+    // Stripe size and number of writes in flight of write_all for the buffers
+    // larger than a stripe.
+    pub fn set_striped_write(&mut self, options: StripedWriteOptions) {
+        self.striped_write = options;
+    }
+
     // -----------------------------------------------------------------------------
     pub fn write_all(&mut self,
-                     mut buff: Vec<u8>, 
+                     buff: Vec<u8>, 
                      callback: Box<Fn(Result<(), String>)>) {
-        let byte_to_write = buff.len();
-        self.adjust_write_buffer(&mut buff);
-        let async_data = Box::new(AsyncData::new_write_data(self.file,
-                                                            buff,
-                                                            byte_to_write,
-                                                            callback));
+        let cluster_size = self.cluster_size;
+        let striped_write = self.striped_write;
+        let throttle = self.throttle();
+        let cost = buff.len();
+        let buff = RefCell::new(Some(buff));
+        let access = Access::Write(0, u64::max_value());
+
+        self.invalidate_read_ahead(0, u64::max_value());
+        let issue = with_handle(self.weak_handle(), Box::new(move |file, callback| {
+            let buff = buff.borrow_mut().take().expect("write_all started twice");
 
-        write_file_async_data(self.file, async_data);
+            start_write_all(file, cluster_size, &striped_write, throttle.clone(), buff, callback)
+        }));
+        let issue = ordered_write(self.sequencer.clone(), access, issue);
+
+        admit_write(self.in_flight_limiter.clone(), cost, callback, issue);
     }
 
     // -----------------------------------------------------------------------------
@@ -71,14 +260,501 @@ impl File {
                                      approximate_read_size: usize,
                                      callback: Box<Fn(Result<&[u8], String>)>) {
         let read_size = self.compute_buffer_size(approximate_read_size);
-        let async_data = Box::new(AsyncData::new_read_data(self.file, read_size, callback));
+        let throttle = self.throttle();
+        let issue = with_handle_read(self.weak_handle(), Box::new(move |file, callback| {
+            let callback = RefCell::new(Some(callback));
+
+            throttle.submit(IoKind::Read, read_size, Box::new(move |request| {
+                let callback = callback.borrow_mut().take().expect("read_all started twice");
+                let callback: Box<Fn(Result<&[u8], String>)> = Box::new(move |result| {
+                    let _request = &request;
 
-        read_file_async_data(self.file, async_data);
+                    callback.as_ref()(result)
+                });
+
+                read_file_async_data(file, Box::new(AsyncData::new_read_data(file, read_size, callback)));
+            }));
+        }));
+        let issue = ordered_read(self.sequencer.clone(), Access::Read(0, u64::max_value()), issue);
+
+        admit_read(self.in_flight_limiter.clone(), read_size, callback, issue);
+    }
+
+    // -----------------------------------------------------------------------------
+    // With read-ahead, read_all keeps several chunk reads in flight instead of
+    // a single read of the whole file, and once the read_at calls are found
+    // sequential the next chunks are read ahead of them.
+    pub fn set_read_ahead(&mut self, options: Option<ReadAheadOptions>) {
+        let cluster_size = self.cluster_size;
+
+        self.read_ahead = options.map(|options| ReadAhead::new(options, cluster_size));
     }
 
     // -----------------------------------------------------------------------------
+    // Chunk size and number of reads in flight of read_all without read-ahead.
+    pub fn set_parallel_read(&mut self, options: ParallelReadOptions) {
+        self.parallel_read = options;
+    }
+
+    // -----------------------------------------------------------------------------
+    // The file length and its allocated extents are queried first: the buffer
+    // is allocated once, the holes of a sparse file are not read and what is
+    // appended while the file is read is not returned.
     pub fn read_all(&mut self, callback: Box<Fn(Result<&[u8], String>)>) {
-        self.read_all_with_buffer_size(1024, callback)
+        let cluster_size = self.cluster_size;
+        let read_ahead = self.read_ahead.as_ref().map(|read_ahead| read_ahead.options);
+        let parallel_read = self.parallel_read;
+        let throttle = self.throttle();
+        let cost = match get_file_size_ex(self.handle()) {
+            Ok(file_size) => file_size as usize,
+            Err(error) => return callback.as_ref()(Err(error)),
+        };
+        let access = Access::Read(0, u64::max_value());
+        let issue = with_handle_read(self.weak_handle(), Box::new(move |file, callback| {
+            let throttle = throttle.clone();
+
+            match read_ahead {
+                Some(ref options) => read_all_ahead(file, options, cluster_size, throttle, callback),
+                None => read_all_parallel(file, &parallel_read, cluster_size, throttle, callback),
+            }
+        }));
+        let issue = ordered_read(self.sequencer.clone(), access, issue);
+
+        admit_read(self.in_flight_limiter.clone(), cost, callback, issue);
+    }
+
+    // -----------------------------------------------------------------------------
+    // Reads len bytes at offset, less at the end of the file. Any offset and
+    // length can be read: the read is aligned to the cluster size.
+    pub fn read_at(&self, offset: u64, len: usize, callback: Box<Fn(Result<&[u8], String>)>) {
+        self.read_at_with_priority(offset, len, self.priority, callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn read_at_with_priority(&self,
+                                 offset: u64,
+                                 len: usize,
+                                 priority: Priority,
+                                 callback: Box<Fn(Result<&[u8], String>)>) {
+        let coalescer = self.coalescer.clone();
+        let read_ahead = self.read_ahead.clone();
+        let access = Access::Read(offset, offset + len as u64);
+        let issue = ordered_read(self.sequencer.clone(), access, Box::new(move |callback| {
+            match read_ahead {
+                Some(ref read_ahead) => {
+                    ReadAhead::read_at(read_ahead, &coalescer, priority, offset, len, callback)
+                }
+                None => Coalescer::read_at(&coalescer, priority, offset, len, callback),
+            }
+        }));
+
+        admit_read(self.in_flight_limiter.clone(), len, callback, issue);
+    }
+
+    // -----------------------------------------------------------------------------
+    // The offset and the size of the data must be multiples of the cluster
+    // size. The reads and writes in flight at the same time are not ordered.
+    pub fn write_at(&self, offset: u64, data: Vec<u8>, callback: Box<Fn(Result<(), String>)>) {
+        self.write_at_with_priority(offset, data, self.priority, callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn write_at_with_priority(&self,
+                                  offset: u64,
+                                  data: Vec<u8>,
+                                  priority: Priority,
+                                  callback: Box<Fn(Result<(), String>)>) {
+        let coalescer = self.coalescer.clone();
+        let cost = data.len();
+        let access = Access::Write(offset, offset + cost as u64);
+
+        self.invalidate_read_ahead(offset, offset + cost as u64);
+        let data = RefCell::new(Some(data));
+        let issue = ordered_write(self.sequencer.clone(), access, Box::new(move |callback| {
+            let data = data.borrow_mut().take().expect("write_at started twice");
+
+            Coalescer::write_at(&coalescer, priority, offset, data, callback)
+        }));
+
+        admit_write(self.in_flight_limiter.clone(), cost, callback, issue);
+    }
+
+    // -----------------------------------------------------------------------------
+    // Priority of the operations of the file in the scheduler, except the
+    // read_at and write_at given their own. It is also the io priority hint
+    // of the handle, for all its operations: Bulk is a low hint, the others a
+    // normal one.
+    pub fn set_priority(&mut self, priority: Priority) -> Result<(), String> {
+        let mut hint_info = FILE_IO_PRIORITY_HINT_INFO {
+            PriorityHint: match priority {
+                Priority::Bulk => IoPriorityHintLow,
+                Priority::Latency | Priority::Normal => IoPriorityHintNormal,
+            },
+        };
+
+        try!(set_file_information_by_handle(self.handle(), FileIoPriorityHintInfo, &mut hint_info));
+        self.priority = priority;
+        Ok(())
+    }
+
+    // -----------------------------------------------------------------------------
+    // With coalescing, the read_at and write_at calls past max_in_flight are
+    // queued, then the adjacent ones are merged into larger requests. It is on
+    // by default with CoalesceOptions::new(), None sends every call right away.
+    pub fn set_coalescing(&mut self, options: Option<CoalesceOptions>) {
+        Coalescer::set_options(&self.coalescer, options.unwrap_or(CoalesceOptions::disabled()))
+    }
+
+    // -----------------------------------------------------------------------------
+    // Limits all the reads and writes of the file, the same limiter can be
+    // shared by a group of files. The long operations are charged chunk by
+    // chunk. A copy counts as reads of the source and writes of the
+    // destination, each through its own limiter.
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<Arc<RateLimiter>>) {
+        self.coalescer.set_rate_limiter(rate_limiter.clone());
+        self.rate_limiter = rate_limiter;
+    }
+
+    // -----------------------------------------------------------------------------
+    // Bounds the operations of read_all, read_all_with_buffer_size,
+    // write_all, read_at, write_at and write_barrier in flight, the same
+    // limiter can be shared by a group of files. The operations waiting for room fail if the File is dropped
+    // first, as the ones waiting for the operations ordered before them and
+    // the read_at and write_at waiting for the rate limiters or the scheduler.
+    // The operations already started go on until they are done.
+    pub fn set_in_flight_limiter(&mut self, limiter: Option<Arc<InFlightLimiter>>) {
+        self.in_flight_limiter = limiter;
+    }
+
+    // -----------------------------------------------------------------------------
+    // In ordered mode, read_all, read_all_with_buffer_size, write_all, read_at
+    // and write_at wait for the operations submitted before them on
+    // overlapping ranges to be applied, and are called back in submission
+    // order. The data read is then copied.
+    pub fn set_ordered(&mut self, ordered: bool) {
+        self.sequencer = if ordered { Some(Sequencer::new()) } else { None };
+    }
+
+    // -----------------------------------------------------------------------------
+    // In ordered mode, waits for the operations submitted before it and
+    // flushes them to disk before any operation submitted after it starts. It
+    // counts as an operation of no bytes for the in-flight limiters.
+    pub fn write_barrier(&self, callback: Box<Fn(Result<(), String>)>) {
+        if self.sequencer.is_none() {
+            return callback.as_ref()(Err("Error write_barrier: the file is not ordered.".to_string()));
+        }
+
+        let issue = with_handle(self.weak_handle(), Box::new(move |file, callback| {
+            post_task(Box::new(move |result| {
+                callback.as_ref()(result.and_then(|_| flush_file_buffers(file)));
+            }));
+        }));
+        let issue = ordered_write(self.sequencer.clone(), Access::Barrier, issue);
+
+        admit_write(self.in_flight_limiter.clone(), 0, callback, issue);
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn metadata(&self, callback: Box<Fn(Result<Metadata, String>)>) {
+        post_file_task(self.weak_handle(), Box::new(query_metadata), callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Calls back once the whole file is locked for this handle, other handles
+    // can still lock it shared. The lock waits in the completion port while it
+    // is taken, without holding an io worker.
+    pub fn lock_shared(&self, callback: Box<Fn(Result<(), String>)>) {
+        self.lock_range(0, u64::max_value(), false, callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn lock_exclusive(&self, callback: Box<Fn(Result<(), String>)>) {
+        self.lock_range(0, u64::max_value(), true, callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Returns false without waiting if another handle holds an exclusive lock.
+    pub fn try_lock_shared(&self) -> Result<bool, String> {
+        lock_file_ex(self.handle(), LOCKFILE_FAIL_IMMEDIATELY, 0, u64::max_value())
+    }
+
+    // -----------------------------------------------------------------------------
+    // Returns false without waiting if another handle holds a lock.
+    pub fn try_lock(&self) -> Result<bool, String> {
+        lock_file_ex(self.handle(),
+                     LOCKFILE_FAIL_IMMEDIATELY | LOCKFILE_EXCLUSIVE_LOCK,
+                     0,
+                     u64::max_value())
+    }
+
+    // -----------------------------------------------------------------------------
+    // Unlocks a lock taken on the whole file.
+    pub fn unlock(&self) -> Result<(), String> {
+        self.unlock_range(0, u64::max_value())
+    }
+
+    // -----------------------------------------------------------------------------
+    // Byte-range lock, mandatory on Windows: the reads and writes of the range
+    // through other handles fail while it is held. The range may be beyond the
+    // end of the file.
+    pub fn lock_range(&self,
+                      offset: u64,
+                      len: u64,
+                      exclusive: bool,
+                      callback: Box<Fn(Result<(), String>)>) {
+        let flags = if exclusive { LOCKFILE_EXCLUSIVE_LOCK } else { 0 };
+        let issue = with_handle(self.weak_handle(), Box::new(move |file, callback| {
+            let async_data = Box::new(AsyncData::new_lock_data(file, offset, callback));
+
+            lock_file_async_data(file, flags, len, async_data)
+        }));
+
+        issue.as_ref()(callback);
+    }
+
+    // -----------------------------------------------------------------------------
+    // The range must be exactly the one of a lock held by this handle.
+    pub fn unlock_range(&self, offset: u64, len: u64) -> Result<(), String> {
+        unlock_file_ex(self.handle(), offset, len)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Gives a file created by tempfile its final path, replacing the file
+    // there if any, and keeps it once dropped. The path must be on the same
+    // volume.
+    pub fn persist<P: AsRef<Path>>(&self, path: P, callback: Box<Fn(Result<(), String>)>) {
+        let path = path.as_ref().to_path_buf();
+
+        post_file_task(self.weak_handle(), Box::new(move |file| persist_file(file, &path)), callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Flushes the file data and metadata to the disk.
+    pub fn sync_all(&self, callback: Box<Fn(Result<(), String>)>) {
+        post_file_task(self.weak_handle(), Box::new(flush_file_buffers), callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Truncates or extends the file, the extension reads as zeros.
+    pub fn set_len(&self, len: u64, callback: Box<Fn(Result<(), String>)>) {
+        self.invalidate_read_ahead(0, u64::max_value());
+
+        post_file_task(self.weak_handle(), Box::new(move |file| set_file_len(file, len)), callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Reserves the disk space for the range, extending the file as
+    // posix_fallocate does if the range ends after it.
+    pub fn allocate(&self, offset: u64, len: u64, callback: Box<Fn(Result<(), String>)>) {
+        post_file_task(self.weak_handle(),
+                       Box::new(move |file| allocate_file_range(file, offset, len)),
+                       callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Makes the file sparse and deallocates the range, which then reads as
+    // zeros. Only whole clusters are deallocated, the edges are zeroed.
+    pub fn punch_hole(&self, offset: u64, len: u64, callback: Box<Fn(Result<(), String>)>) {
+        self.invalidate_read_ahead(offset, offset.saturating_add(len));
+
+        post_file_task(self.weak_handle(),
+                       Box::new(move |file| {
+                           set_sparse(file).and_then(|_| set_zero_data(file, offset, len))
+                       }),
+                       callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Writes zeros over a range inside the file, keeping it allocated unless
+    // the file is sparse.
+    pub fn zero_range(&self, offset: u64, len: u64, callback: Box<Fn(Result<(), String>)>) {
+        self.invalidate_read_ahead(offset, offset.saturating_add(len));
+
+        post_file_task(self.weak_handle(),
+                       Box::new(move |file| set_zero_data(file, offset, len)),
+                       callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // The allocated ranges in offset order, the gaps between them are holes.
+    pub fn allocated_extents(&self, callback: Box<Fn(Result<Vec<Extent>, String>)>) {
+        post_file_task(self.weak_handle(), Box::new(query_allocated_extents), callback)
+    }
+
+    // -----------------------------------------------------------------------------
+    // Copies a range to another file without going through the caller, cloning
+    // the blocks when the file system supports it. The offsets must be
+    // multiples of the cluster size, and the length too unless the range ends
+    // the destination. The callback receives the number of bytes copied.
+    pub fn copy_range_to(&self,
+                         dst: &File,
+                         src_offset: u64,
+                         dst_offset: u64,
+                         len: u64,
+                         progress: Box<Fn(u64)>,
+                         callback: Box<Fn(Result<u64, String>)>) {
+        let src = self.weak_handle();
+        // The writes to the destination have the priority of the copy.
+        let throttles = (self.throttle(),
+                         Throttle { priority: self.priority, rate_limiter: dst.rate_limiter.clone() });
+        let dst = dst.weak_handle();
+        let cluster_size = self.cluster_size;
+        let callbacks = RefCell::new(Some((progress, callback)));
+
+        post_task(Box::new(move |result| {
+            let (progress, callback) = callbacks.borrow_mut().
+                take().
+                expect("copy_range_to started twice");
+            let handles = result.
+                and_then(|_| FileHandle::start(&src)).
+                and_then(|src| FileHandle::start(&dst).map(|dst| (src, dst)));
+
+            match handles {
+                Ok((src, dst)) => {
+                    copy_range(src.raw(), dst.raw(), src_offset, dst_offset, len, cluster_size,
+                               throttles.clone(), progress, holding(vec![src, dst], callback))
+                }
+                Err(error) => callback.as_ref()(Err(error)),
+            }
+        }));
+    }
+
+    // -----------------------------------------------------------------------------
+    // Sends a range of the file to a connected socket without copying it
+    // through the caller. The offset must be a multiple of the cluster size
+    // and the socket must stay open until the callback, which receives the
+    // number of bytes sent.
+    pub fn send_to<S: AsRawSocket>(&self,
+                                   socket: &S,
+                                   offset: u64,
+                                   len: u64,
+                                   callback: Box<Fn(Result<u64, String>)>) {
+        let file = self.weak_handle();
+        let socket = socket.as_raw_socket() as SOCKET;
+        let cluster_size = self.cluster_size as u64;
+        let throttle = self.throttle();
+        let callback = RefCell::new(Some(callback));
+
+        // The length to send is known once the file size is read.
+        post_task(Box::new(move |result| {
+            let callback = callback.borrow_mut().take().expect("send_to started twice");
+            let handle = match result.and_then(|_| FileHandle::start(&file)) {
+                Ok(handle) => handle,
+                Err(error) => return callback.as_ref()(Err(error)),
+            };
+            let file = handle.raw();
+            let len = match send_len(file, offset, len, cluster_size) {
+                Ok(len) => len,
+                Err(error) => return callback.as_ref()(Err(error)),
+            };
+            let send_range = Arc::new(SendRange {
+                socket: socket,
+                file: file,
+                offset: offset,
+                len: len,
+                chunk_size: max(cluster_size, (SEND_CHUNK_SIZE / cluster_size) * cluster_size),
+                throttle: throttle.clone(),
+                callback: holding(vec![handle], callback),
+            });
+
+            send_next(&send_range, 0);
+        }));
+    }
+
+    // -----------------------------------------------------------------------------
+    // Same as write_all but the completion is pushed to the queue under the
+    // returned token. The other _queued methods do the same for their
+    // operation.
+    pub fn write_all_queued(&mut self,
+                            buff: Vec<u8>,
+                            queue: &CompletionQueue) -> CompletionToken {
+        let (token, callback) = queue.callback(|_| CompletionData::Write);
+
+        self.write_all(buff, callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn read_all_queued(&mut self, queue: &CompletionQueue) -> CompletionToken {
+        let (token, callback) = queue.read_callback();
+
+        self.read_all(callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn read_at_queued(&self,
+                          offset: u64,
+                          len: usize,
+                          queue: &CompletionQueue)
+                          -> CompletionToken {
+        let (token, callback) = queue.read_callback();
+
+        self.read_at(offset, len, callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn write_at_queued(&self,
+                           offset: u64,
+                           data: Vec<u8>,
+                           queue: &CompletionQueue)
+                           -> CompletionToken {
+        let (token, callback) = queue.callback(|_| CompletionData::Write);
+
+        self.write_at(offset, data, callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn metadata_queued(&self, queue: &CompletionQueue) -> CompletionToken {
+        let (token, callback) = queue.callback(CompletionData::Metadata);
+
+        self.metadata(callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn set_len_queued(&self, len: u64, queue: &CompletionQueue) -> CompletionToken {
+        let (token, callback) = queue.callback(|_| CompletionData::Write);
+
+        self.set_len(len, callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    // Without progress: only the number of bytes copied is pushed.
+    pub fn copy_range_to_queued(&self,
+                                dst: &File,
+                                src_offset: u64,
+                                dst_offset: u64,
+                                len: u64,
+                                queue: &CompletionQueue)
+                                -> CompletionToken {
+        let (token, callback) = queue.callback(CompletionData::Transferred);
+
+        self.copy_range_to(dst, src_offset, dst_offset, len, Box::new(|_| {}), callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    pub fn send_to_queued<S: AsRawSocket>(&self,
+                                          socket: &S,
+                                          offset: u64,
+                                          len: u64,
+                                          queue: &CompletionQueue)
+                                          -> CompletionToken {
+        let (token, callback) = queue.callback(CompletionData::Transferred);
+
+        self.send_to(socket, offset, len, callback);
+        token
+    }
+
+    // -----------------------------------------------------------------------------
+    fn invalidate_read_ahead(&self, offset: u64, end: u64) {
+        if let Some(ref read_ahead) = self.read_ahead {
+            read_ahead.invalidate(offset, end);
+        }
     }
 
     // -----------------------------------------------------------------------------
@@ -91,29 +767,186 @@ impl File {
             buffer_size
         }
     }
+}
 
-    // -----------------------------------------------------------------------------
-    fn adjust_write_buffer(&self, buff: &mut Vec<u8>) {
-        let new_size = ((buff.len() + self.cluster_size - 1) / self.cluster_size) *
-                       self.cluster_size;
-        buff.reserve(new_size);
+// -----------------------------------------------------------------------------
+// Runs the blocking task on an io worker with the handle, unless the File is
+// dropped first.
+fn post_file_task<R: 'static>(file: Weak<FileHandle>,
+                              task: Box<Fn(HANDLE) -> Result<R, String>>,
+                              callback: Box<Fn(Result<R, String>)>) {
+    post_task(Box::new(move |result| {
+        let result = result.
+            and_then(|_| FileHandle::start(&file)).
+            and_then(|handle| task.as_ref()(handle.raw()));
 
-        unsafe {
-            buff.set_len(new_size);
-        }
+        callback.as_ref()(result);
+    }));
+}
+
+// -----------------------------------------------------------------------------
+// A buffer larger than a stripe is written stripe by stripe, each one through
+// the throttle.
+fn start_write_all(file: HANDLE,
+                   cluster_size: usize,
+                   striped_write: &StripedWriteOptions,
+                   throttle: Throttle,
+                   mut buff: Vec<u8>,
+                   callback: Box<Fn(Result<(), String>)>) {
+    let byte_to_write = buff.len();
+    adjust_write_buffer(&mut buff, cluster_size);
+    if buff.len() > striped_write.aligned_stripe_size(cluster_size) {
+        return write_all_striped(file,
+                                 buff,
+                                 byte_to_write,
+                                 striped_write,
+                                 cluster_size,
+                                 throttle,
+                                 callback);
+    }
+
+    let cost = buff.len();
+    let write = RefCell::new(Some((buff, callback)));
+
+    throttle.submit(IoKind::Write, cost, Box::new(move |request| {
+        let (buff, callback) = write.borrow_mut().take().expect("write_all started twice");
+        let callback: Box<Fn(Result<(), String>)> = Box::new(move |result| {
+            let _request = &request;
+
+            callback.as_ref()(result)
+        });
+
+        write_file_async_data(file, Box::new(AsyncData::new_write_data(file, buff, byte_to_write, callback)));
+    }));
+}
+
+// -----------------------------------------------------------------------------
+fn adjust_write_buffer(buff: &mut Vec<u8>, cluster_size: usize) {
+    let new_size = ((buff.len() + cluster_size - 1) / cluster_size) * cluster_size;
+    buff.reserve(new_size);
+
+    unsafe {
+        buff.set_len(new_size);
     }
 }
 
+// -----------------------------------------------------------------------------
+pub(crate) fn set_file_len(file: HANDLE, len: u64) -> Result<(), String> {
+    let mut end_of_file_info = FILE_END_OF_FILE_INFO { EndOfFile: len as i64 };
+
+    set_file_information_by_handle(file, FileEndOfFileInfo, &mut end_of_file_info)
+}
+
+// -----------------------------------------------------------------------------
+fn allocate_file_range(file: HANDLE, offset: u64, len: u64) -> Result<(), String> {
+    let standard_info: FILE_STANDARD_INFO =
+        try!(get_file_information_by_handle_ex(file, FileStandardInfo));
+    let end = match offset.checked_add(len) {
+        Some(end) if end <= i64::max_value() as u64 => end,
+        _ => return Err(format!("Error allocate: the range of {} bytes at {} is too large.",
+                                len,
+                                offset)),
+    };
+
+    // A smaller allocation size would truncate the file.
+    if end > standard_info.AllocationSize as u64 {
+        let mut allocation_info = FILE_ALLOCATION_INFO { AllocationSize: end as i64 };
+
+        try!(set_file_information_by_handle(file, FileAllocationInfo, &mut allocation_info));
+    }
+    if end > standard_info.EndOfFile as u64 {
+        try!(set_file_len(file, end));
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers. The length is cut at the end of the file.
+fn send_len(file: HANDLE, offset: u64, len: u64, cluster_size: u64) -> Result<u64, String> {
+    if offset % cluster_size != 0 {
+        return Err(format!("Error send_to: offset must be a multiple of {}.", cluster_size));
+    }
+
+    let file_size = try!(get_file_size_ex(file));
+
+    Ok(min(len, file_size.saturating_sub(offset)))
+}
+
+// -----------------------------------------------------------------------------
+struct SendRange {
+    socket: SOCKET,
+    file: HANDLE,
+    offset: u64,
+    len: u64,
+    // A multiple of the cluster size.
+    chunk_size: u64,
+    throttle: Throttle,
+    callback: Box<Fn(Result<u64, String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// Sends the range chunk by chunk, each one through the throttle then on an io
+// worker.
+fn send_next(send_range: &Arc<SendRange>, sent: u64) {
+    if sent >= send_range.len {
+        return (send_range.callback)(Ok(sent));
+    }
+
+    let size = min(send_range.len - sent, send_range.chunk_size);
+    let send_range_chunk = send_range.clone();
+
+    send_range.throttle.submit(IoKind::Read, size as usize, Box::new(move |request| {
+        let send_range = send_range_chunk.clone();
+        let request = RefCell::new(Some(request));
+
+        post_task(Box::new(move |result| {
+            let sent_chunk = result.and_then(|_| {
+                transmit_file(send_range.socket,
+                              send_range.file,
+                              send_range.offset + sent,
+                              size as u32)
+            });
+
+            drop(request.borrow_mut().take());
+            match sent_chunk {
+                // The file was cut before the end of the range.
+                Ok(0) => {
+                    (send_range.callback)(Err(format!("Error send_to: nothing sent at offset {}.",
+                                                      send_range.offset + sent)))
+                }
+                Ok(sent_chunk) => send_next(&send_range, sent + sent_chunk as u64),
+                Err(error) => (send_range.callback)(Err(error)),
+            }
+        }));
+    }));
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers.
+fn persist_file(file: HANDLE, path: &Path) -> Result<(), String> {
+    try!(set_delete_on_close(file, false));
+    set_file_rename_info(file, path, true).map_err(|error| {
+        let _ = set_delete_on_close(file, true);
+        error
+    })
+}
+
+// -----------------------------------------------------------------------------
+fn set_delete_on_close(file: HANDLE, delete: bool) -> Result<(), String> {
+    let mut disposition_info = FILE_DISPOSITION_INFO {
+        DeleteFile: if delete { TRUE } else { FALSE },
+    };
+
+    set_file_information_by_handle(file, FileDispositionInfo, &mut disposition_info)
+}
+
 // -----------------------------------------------------------------------------
 impl Drop for File {
     
     // -------------------------------------------------------------------------
+    // The handle is closed now, or once the operations in flight are done.
     fn drop(&mut self) {
-        unsafe {
-            println!("Drop file");
-            if CloseHandle(self.file) == 0 {
-                panic!("Cannot close file");
-            }
-        }
+        println!("Drop file");
+        self.file.set_dropped();
     }
 }
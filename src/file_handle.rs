@@ -0,0 +1,124 @@
+use winapi::HANDLE;
+
+use kernel32::CloseHandle;
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+// -----------------------------------------------------------------------------
+// The handle of a File. The operations waiting to start only hold a Weak to
+// it, and fail once the File is dropped. The operations started hold it until
+// their callback, the handle is closed after the last of them.
+pub(crate) struct FileHandle {
+    handle: HANDLE,
+    dropped: AtomicBool,
+}
+
+// -----------------------------------------------------------------------------
+impl FileHandle {
+    // -------------------------------------------------------------------------
+    pub(crate) fn new(handle: HANDLE) -> Arc<FileHandle> {
+        Arc::new(FileHandle {
+            handle: handle,
+            dropped: AtomicBool::new(false),
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn raw(&self) -> HANDLE {
+        self.handle
+    }
+
+    // -------------------------------------------------------------------------
+    // Called by the File when it is dropped, the operations which start after
+    // fail even if the handle is still held by others.
+    pub(crate) fn set_dropped(&self) {
+        self.dropped.store(true, Ordering::SeqCst);
+    }
+
+    // -------------------------------------------------------------------------
+    // The handle for an operation about to start, which keeps it open as long
+    // as the operation holds it.
+    pub(crate) fn start(file: &Weak<FileHandle>) -> Result<Arc<FileHandle>, String> {
+        match file.upgrade() {
+            Some(handle) => {
+                if handle.dropped.load(Ordering::SeqCst) {
+                    Err("Error the file is dropped.".to_string())
+                } else {
+                    Ok(handle)
+                }
+            }
+            None => Err("Error the file is dropped.".to_string()),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for FileHandle {
+    // -------------------------------------------------------------------------
+    fn drop(&mut self) {
+        unsafe {
+            if CloseHandle(self.handle) == 0 {
+                panic!("Cannot close file");
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// The handles are released before the callback is called, so that the file is
+// closed once the caller sees the last operation complete and drops the File.
+pub(crate) fn holding<R: 'static>(handles: Vec<Arc<FileHandle>>,
+                                  callback: Box<Fn(Result<R, String>)>)
+                                  -> Box<Fn(Result<R, String>)> {
+    let handles = RefCell::new(handles);
+
+    Box::new(move |result| {
+        handles.borrow_mut().clear();
+        callback.as_ref()(result)
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Same as holding, for the callbacks receiving the data read.
+pub(crate) fn holding_read(handles: Vec<Arc<FileHandle>>,
+                           callback: Box<Fn(Result<&[u8], String>)>)
+                           -> Box<Fn(Result<&[u8], String>)> {
+    let handles = RefCell::new(handles);
+
+    Box::new(move |result| {
+        handles.borrow_mut().clear();
+        callback.as_ref()(result)
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Issue starts the operation on the handle with the callback it is given,
+// which holds the handle. The callback gets an error instead if the File is
+// dropped first.
+pub(crate) fn with_handle<R: 'static>(file: Weak<FileHandle>,
+                                      issue: Box<Fn(HANDLE, Box<Fn(Result<R, String>)>)>)
+                                      -> Box<Fn(Box<Fn(Result<R, String>)>)> {
+    Box::new(move |callback| {
+        match FileHandle::start(&file) {
+            Ok(handle) => issue.as_ref()(handle.raw(), holding(vec![handle], callback)),
+            Err(error) => callback.as_ref()(Err(error)),
+        }
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Same as with_handle, for the callbacks receiving the data read.
+pub(crate) fn with_handle_read(file: Weak<FileHandle>,
+                               issue: Box<Fn(HANDLE, Box<Fn(Result<&[u8], String>)>)>)
+                               -> Box<Fn(Box<Fn(Result<&[u8], String>)>)> {
+    Box::new(move |callback| {
+        match FileHandle::start(&file) {
+            Ok(handle) => issue.as_ref()(handle.raw(), holding_read(vec![handle], callback)),
+            Err(error) => callback.as_ref()(Err(error)),
+        }
+    })
+}
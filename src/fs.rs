@@ -0,0 +1,109 @@
+use tools::post_task;
+
+use std::fs::DirEntry;
+use std::io;
+use std::path::Path;
+use std::os::windows::fs::symlink_dir;
+use std::os::windows::fs::symlink_file;
+
+// -----------------------------------------------------------------------------
+pub fn remove_file<P: AsRef<Path>>(path: P, callback: Box<Fn(Result<(), String>)>) {
+    let path = path.as_ref().to_path_buf();
+
+    post_fs_task(move || {
+        ::std::fs::remove_file(&path).map_err(|error| io_error_message("remove", &path, error))
+    }, callback);
+}
+
+// -----------------------------------------------------------------------------
+// Replaces the destination if it exists.
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P,
+                                              to: Q,
+                                              callback: Box<Fn(Result<(), String>)>) {
+    let from = from.as_ref().to_path_buf();
+    let to = to.as_ref().to_path_buf();
+
+    post_fs_task(move || {
+        ::std::fs::rename(&from, &to).map_err(|error| io_error_message("rename", &from, error))
+    }, callback);
+}
+
+// -----------------------------------------------------------------------------
+pub fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(original: P,
+                                                 link: Q,
+                                                 callback: Box<Fn(Result<(), String>)>) {
+    let original = original.as_ref().to_path_buf();
+    let link = link.as_ref().to_path_buf();
+
+    post_fs_task(move || {
+        ::std::fs::hard_link(&original, &link).
+            map_err(|error| io_error_message("create hard link", &link, error))
+    }, callback);
+}
+
+// -----------------------------------------------------------------------------
+// Creates a directory or a file symlink depending on what original is. A
+// relative original is relative to the directory of the link.
+pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P,
+                                               link: Q,
+                                               callback: Box<Fn(Result<(), String>)>) {
+    let original = original.as_ref().to_path_buf();
+    let link = link.as_ref().to_path_buf();
+
+    post_fs_task(move || {
+        let target = link.parent().unwrap_or(Path::new("")).join(&original);
+        let result = if target.is_dir() {
+            symlink_dir(&original, &link)
+        } else {
+            symlink_file(&original, &link)
+        };
+
+        result.map_err(|error| io_error_message("create symlink", &link, error))
+    }, callback);
+}
+
+// -----------------------------------------------------------------------------
+pub fn create_dir_all<P: AsRef<Path>>(path: P, callback: Box<Fn(Result<(), String>)>) {
+    let path = path.as_ref().to_path_buf();
+
+    post_fs_task(move || {
+        ::std::fs::create_dir_all(&path).
+            map_err(|error| io_error_message("create directory", &path, error))
+    }, callback);
+}
+
+// -----------------------------------------------------------------------------
+pub fn remove_dir_all<P: AsRef<Path>>(path: P, callback: Box<Fn(Result<(), String>)>) {
+    let path = path.as_ref().to_path_buf();
+
+    post_fs_task(move || {
+        ::std::fs::remove_dir_all(&path).
+            map_err(|error| io_error_message("remove directory", &path, error))
+    }, callback);
+}
+
+// -----------------------------------------------------------------------------
+pub fn read_dir<P: AsRef<Path>>(path: P, callback: Box<Fn(Result<Vec<DirEntry>, String>)>) {
+    let path = path.as_ref().to_path_buf();
+
+    post_fs_task(move || {
+        ::std::fs::read_dir(&path).
+            and_then(|entries| entries.collect()).
+            map_err(|error| io_error_message("read directory", &path, error))
+    }, callback);
+}
+
+// -----------------------------------------------------------------------------
+fn post_fs_task<T, F>(operation: F, callback: Box<Fn(Result<T, String>)>)
+    where F: Fn() -> Result<T, String> + 'static,
+          T: 'static
+{
+    post_task(Box::new(move |result| {
+        callback.as_ref()(result.and_then(|_| operation()));
+    }));
+}
+
+// -----------------------------------------------------------------------------
+fn io_error_message(context: &str, path: &Path, error: io::Error) -> String {
+    format!("Error cannot {} {}: {}.", context, path.to_string_lossy(), error)
+}
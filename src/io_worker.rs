@@ -2,33 +2,25 @@ use tools::read_file_async_data_buffer;
 
 use winapi::INVALID_HANDLE_VALUE;
 use winapi::HANDLE;
-use winapi::FILE_END;
-use winapi::LARGE_INTEGER;
 
 use std::ptr::null_mut;
 use std::thread;
 
-use std::mem::transmute;
 use async_data::AsyncData;
 use async_data::DataType;
+
+use file::set_file_len;
 use std::sync::Once;
 use std::sync::ONCE_INIT;
 
 use win_api_helper::get_queued_completion_status;
-use win_api_helper::set_file_pointer_ex;
-use win_api_helper::set_end_of_file;
 use win_api_helper::create_io_completion_port;
 use win_api_helper::get_system_info;
+use win_api_helper::WinHandle;
+use win_api_helper::CompletionStatus;
 
-//-----------------------------------------------------------------------------
-#[derive(Copy, Clone)]
-struct WinHandle
-{
-	handle: HANDLE
-}
-
-unsafe impl Sync for WinHandle {}
-unsafe impl Send for WinHandle {}
+use operation_slab::operation_slab;
+use operation_slab::token_of;
 
 static INIT_COMPLETION_PORT: Once = ONCE_INIT;
 static mut IO_COMPLETION_PORT: Option<Result<WinHandle, &'static str>> = None;
@@ -64,15 +56,25 @@ fn wait_for_io(win_handle: WinHandle) {
 	loop {
 		let completion_status = get_queued_completion_status(win_handle.handle)
 										.expect("Fatal error ");
-		unsafe {								
-			let data: Box<AsyncData> = transmute(completion_status.overlapped);
-			
-			if completion_status.end_of_file {
-				read_async(data, 0);
-			} else {
-				read_async(data, completion_status.nb_bytes_transferred as usize);
-			}	
-		}
+
+		complete_operation(completion_status);
+	}	
+}
+
+//-----------------------------------------------------------------------------
+// Hands a completion packet to the callback of its operation.
+pub fn complete_operation(completion_status: CompletionStatus) {
+	// The overlapped comes from the slab and its operation is still in it.
+	let token = unsafe { token_of(completion_status.overlapped) };
+	let data = operation_slab().remove(token).
+		expect("Completion of an operation which is not in the slab");
+		
+	if let Some(error) = completion_status.error {
+		data.execute_error_callback(error);
+	} else if completion_status.end_of_file {
+		read_async(data, 0);
+	} else {
+		read_async(data, completion_status.nb_bytes_transferred as usize);
 	}	
 }
 
@@ -94,19 +96,41 @@ fn execute_callback(async_data: &mut AsyncData, nb_bytes_transferred: usize) ->
 				Some(read_size)
 			}
 		},
+		&mut DataType::ReadAt(ref read_at_data) => {
+			async_data.buffer.truncate(nb_bytes_transferred);
+			read_at_data.callback.as_ref()(Ok(&async_data.buffer));
+			None
+		}
+		&mut DataType::ReadInto(ref read_into_data) => {
+			read_into_data.callback.as_ref()(Ok(nb_bytes_transferred));
+			None
+		}
 		&mut DataType::Write(ref mut write_data) => {
-			let file_size = write_data.bytes_to_write as isize - nb_bytes_transferred as isize;
-			
-			let res = set_file_pointer_ex(
-					async_data.file_handle, 
-					file_size as LARGE_INTEGER, 
-					null_mut(),
-					FILE_END).
-				and(set_end_of_file(async_data.file_handle));
+			// The padding is cut: the file ends with the data, as after a
+			// striped write, whatever its length was before.
+			let res = if nb_bytes_transferred < write_data.bytes_to_write {
+				Err(format!("Error short write: {} bytes written instead of {}.",
+							nb_bytes_transferred,
+							write_data.bytes_to_write))
+			} else {
+				set_file_len(async_data.file_handle, write_data.bytes_to_write as u64)
+			};
 				
 			write_data.callback.as_ref()(res);
 			None
 		}
+		&mut DataType::WriteFrom(ref write_from_data) => {
+			write_from_data.callback.as_ref()(Ok(nb_bytes_transferred));
+			None
+		}
+		&mut DataType::Lock(ref lock_data) => {
+			lock_data.callback.as_ref()(Ok(()));
+			None
+		}
+		&mut DataType::Task(ref task_data) => {
+			task_data.task.as_ref()(Ok(()));
+			None
+		}
 	}	
 }
 
@@ -3,19 +3,73 @@ extern crate winapi;
 extern crate time;
 
 pub mod file;
+pub mod completion_queue;
+pub mod metadata;
+pub mod sparse;
+pub mod copy;
+pub mod fs;
+pub mod walk;
+pub mod atomic;
+pub mod read_ahead;
+pub mod parallel_read;
+pub mod striped_write;
+pub mod cache;
+pub mod buffered_writer;
+pub mod coalesce;
+pub mod scheduler;
+pub mod rate_limit;
+pub mod backpressure;
+mod ordering;
+mod file_handle;
 mod win_api_helper;
 mod async_data;
 mod io_worker;
 mod tools;
+mod operation_slab;
 
 
 #[cfg(test)]
 mod test {
     use file::File;
+    use completion_queue::CompletionQueue;
+    use completion_queue::CompletionData;
+    use completion_queue::Completion;
+    use metadata::FileType;
+    use copy;
+    use fs;
+    use walk;
+    use walk::glob_match;
+    use atomic::atomic_write;
+    use atomic::AtomicFile;
+    use read_ahead::ReadAheadOptions;
+    use parallel_read::ParallelReadOptions;
+    use striped_write::StripedWriteOptions;
+    use cache::BlockCache;
+    use cache::BlockCacheOptions;
+    use cache::CachedFile;
+    use buffered_writer::BufferedWriter;
+    use buffered_writer::BufferedWriterOptions;
+    use coalesce::CoalesceOptions;
+    use scheduler::Priority;
+    use scheduler::Scheduler;
+    use scheduler::SchedulerOptions;
+    use rate_limit::RateLimiter;
+    use rate_limit::RateLimits;
+    use rate_limit::IoKind;
+    use backpressure::BackpressureMode;
+    use backpressure::InFlightLimiter;
+    use backpressure::InFlightLimits;
     use std::io::Write;
     use std::io::ErrorKind;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
     use std;
 	use io_worker::add_usize_to_u32_pair;
+	use winapi::WAIT_TIMEOUT;
+	use winapi::WAIT_OBJECT_0;
+	use kernel32::WaitForSingleObject;
 	
     // -----------------------------------------------------------------------------
     struct Notifier {
@@ -60,17 +114,37 @@ mod test {
         (Waiter { pair: pair.clone() }, Notifier { pair: pair })
     }
 
+    // -----------------------------------------------------------------------------
+    fn wait_result<T: 'static>(start: &Fn(Box<Fn(Result<T, String>)>)) -> Result<T, String> {
+        let (waiter, notifier) = create_waiter();
+        let slot = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let slot_clone = slot.clone();
+
+        start(Box::new(move |result| {
+            *slot_clone.lock().unwrap() = Some(result);
+            notifier.notify();
+        }));
+        waiter.wait();
+        let result = slot.lock().unwrap().take().unwrap();
+        result
+    }
+
     // -----------------------------------------------------------------------------
     struct Test {
-        path: &'static str,
+        path: PathBuf,
     }
 
     // -----------------------------------------------------------------------------
     impl Test {
         // -------------------------------------------------------------------------
         fn new() -> Test {
-            let test = Test { path: "test" };
-            std::fs::remove_file(test.path).
+            Test::with_path("test")
+        }
+
+        // -------------------------------------------------------------------------
+        fn with_path<P: Into<PathBuf>>(path: P) -> Test {
+            let test = Test { path: path.into() };
+            std::fs::remove_file(&test.path).
             	or_else(|error| -> std::io::Result<()> { 
             		if error.kind() == ErrorKind::NotFound {
             			Ok(())
@@ -83,7 +157,7 @@ mod test {
 
         // -------------------------------------------------------------------------
         fn create_file(&self, data: &[u8]) {
-            let mut fs = std::fs::File::create(self.path).unwrap();
+            let mut fs = std::fs::File::create(&self.path).unwrap();
 
             fs.write_all(data).unwrap();
         }
@@ -110,7 +184,7 @@ mod test {
             let data = Test::create_data(data_size);
 
             self.create_file(&data);
-            let mut file = File::open(self.path).unwrap();
+            let mut file = File::open(&self.path).unwrap();
             file.read_all(Box::new(move |data_result| {
                 let read_data = data_result.unwrap();
                 assert_eq!(data.len(), read_data.len());
@@ -123,7 +197,7 @@ mod test {
         // -----------------------------------------------------------------------------
         fn check_read(&self, expected_data: Vec<u8>) {
             let (waiter, notifier) = create_waiter();
-            let mut file = File::open(self.path).unwrap();
+            let mut file = File::open(&self.path).unwrap();
             file.read_all(Box::new(move |data_result| {
                 let read_data = data_result.unwrap();
                 assert_eq!(expected_data.len(), read_data.len());
@@ -137,7 +211,7 @@ mod test {
         fn write_sync(&self, data: Vec<u8>) {
             let (waiter, notifier) = create_waiter();
 
-            let mut file = File::create(self.path).unwrap();
+            let mut file = File::create(&self.path).unwrap();
             file.write_all(data,
                            Box::new(move |result| {
                                result.unwrap();
@@ -158,7 +232,7 @@ mod test {
     // -----------------------------------------------------------------------------
     impl Drop for Test {
         fn drop(&mut self) {
-            std::fs::remove_file(self.path).unwrap();
+            std::fs::remove_file(&self.path).unwrap();
         }
     }
 
@@ -166,7 +240,7 @@ mod test {
     #[test]
     fn it_test_create() {
         let test = Test::new();
-        File::create(test.path).unwrap();
+        File::create(&test.path).unwrap();
     }
 
     // -----------------------------------------------------------------------------
@@ -175,7 +249,7 @@ mod test {
         let test = Test::new();
 
         test.create_file(b"data");
-        assert!(File::create(test.path).is_err());
+        assert!(File::create(&test.path).is_err());
     }
 
     // -----------------------------------------------------------------------------
@@ -184,7 +258,7 @@ mod test {
         let test = Test::new();
 
         test.create_file(b"data");
-        File::open(test.path).unwrap();
+        File::open(&test.path).unwrap();
     }
 
     // -----------------------------------------------------------------------------
@@ -192,7 +266,7 @@ mod test {
     fn it_test_open_file_not_exist() {
         let test = Test::new();
 
-        assert!(File::open(test.path).is_err());
+        assert!(File::open(&test.path).is_err());
         test.create_file(b"For Test::Drop");
     }
 
@@ -236,6 +310,1347 @@ mod test {
         test.test_write_all(2 * File::get_cluster_size());
     }
     
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_completion_queue_empty() {
+        let queue = CompletionQueue::new();
+
+        assert!(queue.try_recv().is_none());
+        assert!(queue.poll().is_empty());
+        assert!(queue.wait(std::time::Duration::from_millis(10)).is_none());
+
+        // No deadline can be computed, the wait has none.
+        queue.push(Completion { token: 7, result: Ok(CompletionData::Write) });
+        assert_eq!(7, queue.wait(std::time::Duration::new(u64::max_value(), 0)).unwrap().token);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_completion_queue_write_read() {
+        let test = Test::with_path("test_completion_queue");
+        let queue = CompletionQueue::new();
+        let data = Test::create_data(42);
+        let timeout = std::time::Duration::new(3, 0);
+
+        let mut file = File::create(&test.path).unwrap();
+        let write_token = file.write_all_queued(data.clone(), &queue);
+        let completion = queue.wait(timeout).expect("Timeout!");
+        assert_eq!(write_token, completion.token);
+        match completion.result.unwrap() {
+            CompletionData::Write => {}
+            _ => panic!("Unexpected completion"),
+        }
+
+        drop(file);
+        let mut file = File::open(&test.path).unwrap();
+        let read_token = file.read_all_queued(&queue);
+        assert!(read_token != write_token);
+        let completion = queue.wait(timeout).expect("Timeout!");
+        assert_eq!(read_token, completion.token);
+        match completion.result.unwrap() {
+            CompletionData::Read(read_data) => assert_eq!(data, read_data),
+            _ => panic!("Unexpected completion"),
+        }
+        assert!(queue.try_recv().is_none());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_completion_queue_operations() {
+        let test = Test::with_path("test_completion_queue_operations");
+        let copy_test = Test::with_path("test_completion_queue_operations_copy");
+        let queue = CompletionQueue::new();
+        let timeout = std::time::Duration::new(3, 0);
+        let cluster_size = File::get_cluster_size();
+        let data = Test::create_data(cluster_size);
+        let wait = |token| {
+            let completion = queue.wait(timeout).expect("Timeout!");
+
+            assert_eq!(token, completion.token);
+            completion.result.unwrap()
+        };
+        {
+            let file = File::create(&test.path).unwrap();
+            let copy = File::create(&copy_test.path).unwrap();
+
+            match wait(file.write_at_queued(0, data.clone(), &queue)) {
+                CompletionData::Write => {}
+                _ => panic!("Unexpected completion"),
+            }
+            match wait(file.read_at_queued(0, cluster_size, &queue)) {
+                CompletionData::Read(read_data) => assert_eq!(data, read_data),
+                _ => panic!("Unexpected completion"),
+            }
+            match wait(file.set_len_queued(42, &queue)) {
+                CompletionData::Write => {}
+                _ => panic!("Unexpected completion"),
+            }
+            match wait(file.metadata_queued(&queue)) {
+                CompletionData::Metadata(metadata) => assert_eq!(42, metadata.len()),
+                _ => panic!("Unexpected completion"),
+            }
+            match wait(file.copy_range_to_queued(&copy, 0, 0, 42, &queue)) {
+                CompletionData::Transferred(nb_bytes) => assert_eq!(42, nb_bytes),
+                _ => panic!("Unexpected completion"),
+            }
+        }
+        assert_eq!(&data[..42], &std::fs::read(&copy_test.path).unwrap()[..]);
+        assert!(queue.try_recv().is_none());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_completion_queue_event() {
+        let test = Test::with_path("test_completion_queue_event");
+        let queue = CompletionQueue::with_event().unwrap();
+        let event = queue.event_handle().unwrap();
+
+        assert_eq!(WAIT_TIMEOUT, unsafe { WaitForSingleObject(event, 0) });
+        let mut file = File::create(&test.path).unwrap();
+        let token = file.write_all_queued(Test::create_data(42), &queue);
+        assert_eq!(WAIT_OBJECT_0, unsafe { WaitForSingleObject(event, 3000) });
+        assert_eq!(token, queue.try_recv().unwrap().token);
+        assert_eq!(WAIT_TIMEOUT, unsafe { WaitForSingleObject(event, 0) });
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_metadata() {
+        let test = Test::with_path("test_metadata");
+        let (waiter, notifier) = create_waiter();
+        let before = std::time::SystemTime::now() - std::time::Duration::new(60, 0);
+
+        test.create_file(&Test::create_data(42));
+        let file = File::open(&test.path).unwrap();
+        file.metadata(Box::new(move |metadata_result| {
+            let metadata = metadata_result.unwrap();
+            assert_eq!(42, metadata.len());
+            assert!(metadata.allocation_size() >= 42);
+            assert_eq!(FileType::File, metadata.file_type());
+            assert!(!metadata.readonly());
+            assert!(metadata.modified() > before);
+            assert!(metadata.created() > before);
+            notifier.notify();
+        }));
+        waiter.wait();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_all_empty() {
+        let test = Test::with_path("test_read_all_empty");
+
+        test.test_read_all(0);
+    }
+
+    // -----------------------------------------------------------------------------
+    fn check_len(file: &File, expected_len: u64, expected_allocation_size: u64) {
+        let (waiter, notifier) = create_waiter();
+
+        file.metadata(Box::new(move |metadata_result| {
+            let metadata = metadata_result.unwrap();
+            assert_eq!(expected_len, metadata.len());
+            assert!(metadata.allocation_size() >= expected_allocation_size);
+            notifier.notify();
+        }));
+        waiter.wait();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_set_len() {
+        let test = Test::with_path("test_set_len");
+        let data = Test::create_data(42);
+
+        test.create_file(&data);
+        {
+            let file = File::open(&test.path).unwrap();
+            let (waiter, notifier) = create_waiter();
+
+            file.set_len(10, Box::new(move |result| {
+                result.unwrap();
+                notifier.notify();
+            }));
+            waiter.wait();
+            check_len(&file, 10, 0);
+        }
+        test.check_read(data[..10].to_vec());
+
+        let file = File::open(&test.path).unwrap();
+        let (waiter, notifier) = create_waiter();
+        file.set_len(2000, Box::new(move |result| {
+            result.unwrap();
+            notifier.notify();
+        }));
+        waiter.wait();
+        check_len(&file, 2000, 0);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_allocate() {
+        let test = Test::with_path("test_allocate");
+        let file = File::create(&test.path).unwrap();
+        let (waiter, notifier) = create_waiter();
+
+        file.allocate(0, 8192, Box::new(move |result| {
+            result.unwrap();
+            notifier.notify();
+        }));
+        waiter.wait();
+        check_len(&file, 8192, 8192);
+
+        assert!(wait_result(&|callback| file.allocate(u64::max_value(), 1, callback)).is_err());
+        assert!(wait_result(&|callback| file.allocate(1 << 63, 0, callback)).is_err());
+        check_len(&file, 8192, 8192);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_zero_range() {
+        let test = Test::with_path("test_zero_range");
+        let mut data = Test::create_data(42);
+
+        test.create_file(&data);
+        {
+            let file = File::open(&test.path).unwrap();
+            let (waiter, notifier) = create_waiter();
+
+            file.zero_range(10, 20, Box::new(move |result| {
+                result.unwrap();
+                notifier.notify();
+            }));
+            waiter.wait();
+            assert!(wait_result(&|callback| file.zero_range(10, u64::max_value(), callback)).
+                is_err());
+        }
+        for value in &mut data[10..30] {
+            *value = 0;
+        }
+        test.check_read(data);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_punch_hole() {
+        let test = Test::with_path("test_punch_hole");
+        let hole_size = 64 * 1024;
+        let mut data = Test::create_data(3 * hole_size);
+
+        test.create_file(&data);
+        for value in &mut data[hole_size..2 * hole_size] {
+            *value = 0;
+        }
+        let mut file = File::open(&test.path).unwrap();
+        let (waiter, notifier) = create_waiter();
+        file.punch_hole(hole_size as u64, hole_size as u64, Box::new(move |result| {
+            result.unwrap();
+            notifier.notify();
+        }));
+        waiter.wait();
+
+        let (waiter, notifier) = create_waiter();
+        file.allocated_extents(Box::new(move |extents_result| {
+            let extents = extents_result.unwrap();
+            assert!(!extents.is_empty());
+            for extent in extents {
+                let end = extent.offset + extent.len;
+                assert!(end <= hole_size as u64 || extent.offset >= 2 * hole_size as u64);
+            }
+            notifier.notify();
+        }));
+        waiter.wait();
+
+        let (waiter, notifier) = create_waiter();
+        let expected_data = data.clone();
+        file.read_all(Box::new(move |data_result| {
+            assert_eq!(expected_data, data_result.unwrap());
+            notifier.notify();
+        }));
+        waiter.wait();
+        drop(file);
+        test.check_read(data);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_copy_range_to() {
+        let src_test = Test::with_path("test_copy_range_src");
+        let dst_test = Test::with_path("test_copy_range_dst");
+        let data = Test::create_data(3000);
+        let progress = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        src_test.create_file(&data);
+        {
+            let src = File::open(&src_test.path).unwrap();
+            let dst = File::create(&dst_test.path).unwrap();
+            let (waiter, notifier) = create_waiter();
+            let progress_clone = progress.clone();
+
+            src.copy_range_to(&dst, 0, 0, u64::max_value(),
+                              Box::new(move |copied| {
+                                  progress_clone.store(copied as usize,
+                                                       std::sync::atomic::Ordering::SeqCst);
+                              }),
+                              Box::new(move |result| {
+                                  assert_eq!(3000, result.unwrap());
+                                  notifier.notify();
+                              }));
+            waiter.wait();
+        }
+        assert_eq!(3000, progress.load(std::sync::atomic::Ordering::SeqCst));
+        dst_test.check_read(data);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_copy_range_to_unaligned() {
+        let src_test = Test::with_path("test_copy_range_unaligned_src");
+        let dst_test = Test::with_path("test_copy_range_unaligned_dst");
+
+        src_test.create_file(&Test::create_data(3000));
+        let src = File::open(&src_test.path).unwrap();
+        let dst = File::create(&dst_test.path).unwrap();
+        let (waiter, notifier) = create_waiter();
+        src.copy_range_to(&dst, 10, 0, 100,
+                          Box::new(|_| {}),
+                          Box::new(move |result| {
+                              assert!(result.is_err());
+                              notifier.notify();
+                          }));
+        waiter.wait();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_copy() {
+        let src_test = Test::with_path("test_copy_src");
+        let dst_test = Test::with_path("test_copy_dst");
+        let data = Test::create_data(3000);
+        let (waiter, notifier) = create_waiter();
+
+        src_test.create_file(&data);
+        copy::copy(&src_test.path, &dst_test.path,
+                   Box::new(|copied, total| assert!(copied <= total)),
+                   Box::new(move |result| {
+                       result.unwrap();
+                       notifier.notify();
+                   }));
+        waiter.wait();
+        dst_test.check_read(data);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_send_to() {
+        use std::io::Read;
+        use std::net::TcpListener;
+        use std::net::TcpStream;
+
+        let test = Test::with_path("test_send_to");
+        let data = Test::create_data(3000);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let (waiter, notifier) = create_waiter();
+
+        test.create_file(&data);
+        let file = File::open(&test.path).unwrap();
+        file.send_to(&server, 1024, 1000, Box::new(move |result| {
+            assert_eq!(1000, result.unwrap());
+            notifier.notify();
+        }));
+        waiter.wait();
+
+        let mut received = vec![0; 1000];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&data[1024..2024], &received[..]);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_fs_operations() {
+        let root = Path::new("test_fs_operations");
+        let dir = root.join("a").join("b");
+        let file_path = dir.join("file");
+        let link_path = dir.join("link");
+        let renamed_path = root.join("renamed");
+
+        let _ = std::fs::remove_dir_all(root);
+        wait_result(&|callback| fs::create_dir_all(&dir, callback)).unwrap();
+        assert!(dir.is_dir());
+
+        std::fs::File::create(&file_path).unwrap().write_all(b"data").unwrap();
+        wait_result(&|callback| fs::hard_link(&file_path, &link_path, callback)).unwrap();
+        wait_result(&|callback| fs::rename(&link_path, &renamed_path, callback)).unwrap();
+        assert!(!link_path.exists());
+        assert_eq!(b"data".to_vec(), std::fs::read(&renamed_path).unwrap());
+
+        let entries = wait_result(&|callback| fs::read_dir(root, callback)).unwrap();
+        let mut names: Vec<_> = entries.iter().map(|entry| entry.file_name()).collect();
+        names.sort();
+        assert_eq!(vec![OsString::from("a"), OsString::from("renamed")], names);
+
+        wait_result(&|callback| fs::remove_file(&renamed_path, callback)).unwrap();
+        assert!(!renamed_path.exists());
+        assert!(wait_result(&|callback| fs::remove_file(&renamed_path, callback)).is_err());
+
+        wait_result(&|callback| fs::remove_dir_all(root, callback)).unwrap();
+        assert!(!root.exists());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_glob_match() {
+        assert!(glob_match("*.txt", "file.txt"));
+        assert!(glob_match("*.TXT", "File.txt"));
+        assert!(!glob_match("*.txt", "dir/file.txt"));
+        assert!(glob_match("**/*.txt", "file.txt"));
+        assert!(glob_match("**/*.txt", "a/b/file.txt"));
+        assert!(!glob_match("**/*.txt", "a/b/file.log"));
+        assert!(glob_match("a/**", "a/b/c"));
+        assert!(glob_match("a/?/c", "a/b/c"));
+        assert!(!glob_match("a/?/c", "a/bb/c"));
+        assert!(!glob_match("a?b", "a/b"));
+    }
+
+    // -----------------------------------------------------------------------------
+    fn walk_sync(root: &Path, options: walk::WalkOptions) -> Vec<String> {
+        let (waiter, notifier) = create_waiter();
+        let paths = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let paths_clone = paths.clone();
+        let root_clone = root.to_path_buf();
+
+        walk::walk(root, options,
+                   Box::new(move |entry_result| {
+                       let entry = entry_result.unwrap();
+                       let relative_path = entry.path.strip_prefix(&root_clone).unwrap();
+                       assert_eq!(entry.depth, relative_path.components().count());
+                       assert_eq!(entry.path.is_dir(), entry.metadata.is_dir());
+                       paths_clone.lock().unwrap().push(relative_path.to_string_lossy().replace("\\", "/"));
+                   }),
+                   Box::new(move || notifier.notify()));
+        waiter.wait();
+
+        let mut paths = paths.lock().unwrap().clone();
+        paths.sort();
+        paths
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_walk() {
+        let root = Path::new("test_walk");
+
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root.join("a").join("b")).unwrap();
+        std::fs::create_dir_all(root.join("e")).unwrap();
+        for path in &["a/b/file.txt", "a/file.log", "c.txt", "e/d.txt"] {
+            std::fs::File::create(root.join(path)).unwrap();
+        }
+
+        let mut options = walk::WalkOptions::new();
+        options.max_concurrency = 2;
+        assert_eq!(vec!["a", "a/b", "a/b/file.txt", "a/file.log", "c.txt", "e", "e/d.txt"],
+                   walk_sync(root, options));
+
+        let mut options = walk::WalkOptions::new();
+        options.glob = Some("**/*.txt".to_string());
+        assert_eq!(vec!["a/b/file.txt", "c.txt", "e/d.txt"], walk_sync(root, options));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    // -----------------------------------------------------------------------------
+    fn dir_entries(dir: &Path) -> Vec<OsString> {
+        let mut names: Vec<_> = std::fs::read_dir(dir).unwrap().
+            map(|entry| entry.unwrap().file_name()).
+            collect();
+        names.sort();
+        names
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_atomic_write() {
+        let dir = Path::new("test_atomic_write");
+        let path = dir.join("file");
+        let data = Test::create_data(3000);
+
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        wait_result(&|callback| atomic_write(&path, data.clone(), callback)).unwrap();
+        assert_eq!(data, std::fs::read(&path).unwrap());
+
+        wait_result(&|callback| atomic_write(&path, b"new data".to_vec(), callback)).unwrap();
+        assert_eq!(b"new data".to_vec(), std::fs::read(&path).unwrap());
+        assert_eq!(vec![OsString::from("file")], dir_entries(dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_atomic_file() {
+        let dir = Path::new("test_atomic_file");
+        let path = dir.join("file");
+
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        std::fs::write(&path, b"old data").unwrap();
+        {
+            let atomic_file = std::cell::RefCell::new(AtomicFile::create(&path).unwrap());
+
+            wait_result(&|callback| {
+                atomic_file.borrow_mut().file().write_all(b"lost".to_vec(), callback)
+            }).unwrap();
+            assert_eq!(2, dir_entries(dir).len());
+        }
+        assert_eq!(vec![OsString::from("file")], dir_entries(dir));
+        assert_eq!(b"old data".to_vec(), std::fs::read(&path).unwrap());
+
+        let atomic_file = std::cell::RefCell::new(Some(AtomicFile::create(&path).unwrap()));
+        wait_result(&|callback| {
+            atomic_file.borrow_mut().as_mut().unwrap().file().write_all(b"new data".to_vec(), callback)
+        }).unwrap();
+        wait_result(&|callback| atomic_file.borrow_mut().take().unwrap().commit(callback)).
+            unwrap();
+        assert_eq!(b"new data".to_vec(), std::fs::read(&path).unwrap());
+        assert_eq!(vec![OsString::from("file")], dir_entries(dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_lock() {
+        let test = Test::with_path("test_lock");
+
+        test.create_file(&Test::create_data(42));
+        let first = File::open_shared(&test.path).unwrap();
+        let second = File::open_shared(&test.path).unwrap();
+
+        wait_result(&|callback| first.lock_shared(callback)).unwrap();
+        assert!(second.try_lock_shared().unwrap());
+        assert!(!second.try_lock().unwrap());
+        second.unlock().unwrap();
+        first.unlock().unwrap();
+
+        wait_result(&|callback| first.lock_exclusive(callback)).unwrap();
+        assert!(!second.try_lock_shared().unwrap());
+
+        let (waiter, notifier) = create_waiter();
+        second.lock_exclusive(Box::new(move |result| {
+            result.unwrap();
+            notifier.notify();
+        }));
+        first.unlock().unwrap();
+        waiter.wait();
+        assert!(!first.try_lock().unwrap());
+        second.unlock().unwrap();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_lock_range() {
+        let test = Test::with_path("test_lock_range");
+
+        test.create_file(&Test::create_data(42));
+        let first = File::open_shared(&test.path).unwrap();
+        let second = File::open_shared(&test.path).unwrap();
+
+        wait_result(&|callback| first.lock_range(0, 1024, true, callback)).unwrap();
+        wait_result(&|callback| second.lock_range(1024, 1024, true, callback)).unwrap();
+        assert!(!second.try_lock_shared().unwrap());
+        first.unlock_range(0, 1024).unwrap();
+        second.unlock_range(1024, 1024).unwrap();
+        assert!(second.try_lock().unwrap());
+        second.unlock().unwrap();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_tempfile() {
+        let dir = Path::new("test_tempfile");
+
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        {
+            let first = std::cell::RefCell::new(File::tempfile(dir).unwrap());
+            let second = File::tempfile(dir).unwrap();
+
+            assert_eq!(2, dir_entries(dir).len());
+            wait_result(&|callback| {
+                first.borrow_mut().write_all(b"scratch".to_vec(), callback)
+            }).unwrap();
+            drop(second);
+        }
+        assert!(dir_entries(dir).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_tempfile_persist() {
+        let dir = Path::new("test_tempfile_persist");
+        let path = dir.join("file");
+
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        std::fs::write(&path, b"old data").unwrap();
+        {
+            let file = std::cell::RefCell::new(File::tempfile(dir).unwrap());
+
+            wait_result(&|callback| {
+                file.borrow_mut().write_all(b"persisted".to_vec(), callback)
+            }).unwrap();
+            wait_result(&|callback| file.borrow().persist(&path, callback)).unwrap();
+        }
+        assert_eq!(b"persisted".to_vec(), std::fs::read(&path).unwrap());
+        assert_eq!(vec![OsString::from("file")], dir_entries(dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_all_ahead() {
+        let test = Test::with_path("test_read_all_ahead");
+        let mut options = ReadAheadOptions::new();
+
+        options.queue_depth = 4;
+        options.chunk_size = 4000;
+        for &data_size in [0, 4096, 4096 * 3, 4096 * 20 + 42].iter() {
+            let data = Test::create_data(data_size);
+
+            test.create_file(&data);
+            {
+                let file = std::cell::RefCell::new(File::open(&test.path).unwrap());
+
+                file.borrow_mut().set_read_ahead(Some(options));
+                let read_data = wait_result(&|callback| {
+                    file.borrow_mut().read_all(Box::new(move |result| {
+                        callback(result.map(|read_data| read_data.to_vec()))
+                    }))
+                }).unwrap();
+                assert_eq!(data, read_data);
+            }
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_all_parallel() {
+        let test = Test::with_path("test_read_all_parallel");
+        let mut options = ParallelReadOptions::new();
+
+        options.parallelism = 3;
+        options.chunk_size = 2048;
+        for &data_size in [0, 42, 2048, 2048 * 10, 2048 * 10 + 42].iter() {
+            let data = Test::create_data(data_size);
+
+            test.create_file(&data);
+            {
+                let file = std::cell::RefCell::new(File::open(&test.path).unwrap());
+
+                file.borrow_mut().set_parallel_read(options);
+                let read_data = wait_result(&|callback| {
+                    file.borrow_mut().read_all(Box::new(move |result| {
+                        callback(result.map(|read_data| read_data.to_vec()))
+                    }))
+                }).unwrap();
+                assert_eq!(data, read_data);
+            }
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_write_all_striped() {
+        let mut options = StripedWriteOptions::new();
+
+        options.parallelism = 3;
+        options.stripe_size = 2048;
+        for &data_size in [42, 2048, 2048 * 10, 2048 * 10 + 42].iter() {
+            // File::create fails on an existing file: each size starts anew.
+            let test = Test::with_path("test_write_all_striped");
+            let data = Test::create_data(data_size);
+            {
+                let file = std::cell::RefCell::new(File::create(&test.path).unwrap());
+
+                file.borrow_mut().set_striped_write(options);
+                wait_result(&|callback| {
+                    file.borrow_mut().write_all(data.clone(), callback)
+                }).unwrap();
+            }
+            assert_eq!(data, std::fs::read(&test.path).unwrap());
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    // The single write and the striped write both leave the file with the new
+    // data only.
+    #[test]
+    fn it_test_write_all_over_larger_file() {
+        let test = Test::with_path("test_write_all_over_larger_file");
+        let mut options = StripedWriteOptions::new();
+
+        options.stripe_size = 2048;
+        for &data_size in [42, 2048 * 3 + 42].iter() {
+            let data = Test::create_data(data_size);
+
+            test.create_file(&Test::create_data(2048 * 10));
+            {
+                let file = std::cell::RefCell::new(File::open(&test.path).unwrap());
+
+                file.borrow_mut().set_striped_write(options);
+                wait_result(&|callback| {
+                    file.borrow_mut().write_all(data.clone(), callback)
+                }).unwrap();
+            }
+            assert_eq!(data, std::fs::read(&test.path).unwrap());
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_cached_file() {
+        let test = Test::with_path("test_cached_file");
+        let data = Test::create_data(4096 * 4 + 100);
+        let mut options = BlockCacheOptions::new();
+
+        options.capacity = 4096 * 2;
+        options.nb_shards = 1;
+        let cache = BlockCache::new(options);
+
+        test.create_file(&data);
+        {
+            let file = CachedFile::new(File::open(&test.path).unwrap(), cache.clone());
+            let read_at = |offset: u64, len: usize| {
+                wait_result(&|callback| file.read_at(offset, len, callback)).unwrap()
+            };
+
+            assert_eq!(&data[100..200], &read_at(100, 100)[..]);
+            assert_eq!(&data[150..250], &read_at(150, 100)[..]);
+            assert_eq!(1, cache.stats().misses);
+            assert_eq!(1, cache.stats().hits);
+
+            assert_eq!(&data[4000..4200], &read_at(4000, 200)[..]);
+            assert_eq!(&data[4096 * 4..], &read_at(4096 * 4, 1000)[..]);
+            assert!(read_at(4096 * 5, 10).is_empty());
+            assert_eq!(&data[..], &read_at(0, data.len() + 1)[..]);
+            assert_eq!(&data[4096..], &read_at(4096, usize::max_value())[..]);
+            assert!(read_at(u64::max_value(), 10).is_empty());
+            assert!(cache.stats().evictions > 0);
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_cached_file_single_flight() {
+        let test = Test::with_path("test_cached_file_single_flight");
+        let data = Test::create_data(4096);
+        let cache = BlockCache::new(BlockCacheOptions::new());
+
+        test.create_file(&data);
+        {
+            let file = CachedFile::new(File::open(&test.path).unwrap(), cache.clone());
+            let (waiter, notifier) = create_waiter();
+            let nb_reads = 8;
+            let notifier = std::sync::Arc::new(notifier);
+            let nb_done = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+            for _ in 0..nb_reads {
+                let expected_data = data.clone();
+                let nb_done = nb_done.clone();
+                let notifier = notifier.clone();
+
+                file.read_at(0, 4096, Box::new(move |result| {
+                    assert_eq!(expected_data, result.unwrap());
+                    if nb_done.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1 == nb_reads {
+                        notifier.notify();
+                    }
+                }));
+            }
+            waiter.wait();
+            assert_eq!(nb_reads, cache.stats().hits + cache.stats().misses);
+            assert_eq!(1, cache.stats().reads);
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_buffered_writer() {
+        let test = Test::with_path("test_buffered_writer");
+        let data = Test::create_data(10000);
+        let mut options = BufferedWriterOptions::new();
+
+        options.buffer_size = 2048;
+        options.max_pending_flushes = 2;
+        test.create_file(&data[..100]);
+        {
+            let writer = BufferedWriter::new(File::open_shared(&test.path).unwrap(), options).unwrap();
+
+            for chunk in data[100..5000].chunks(77) {
+                writer.write(chunk).unwrap();
+            }
+            wait_result(&|callback| writer.flush(callback)).unwrap();
+            assert_eq!(&data[..5000], &std::fs::read(&test.path).unwrap()[..]);
+
+            writer.write(&data[5000..]).unwrap();
+            // Called back once the last flush is written and the file closed.
+            let (waiter, notifier) = create_waiter();
+            writer.close(Box::new(move |result| {
+                result.unwrap();
+                notifier.notify();
+            }));
+            waiter.wait();
+        }
+        assert_eq!(data, std::fs::read(&test.path).unwrap());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_buffered_writer_flush_interval() {
+        let test = Test::with_path("test_buffered_writer_flush_interval");
+        let mut options = BufferedWriterOptions::new();
+
+        options.flush_interval = Some(std::time::Duration::from_millis(10));
+        test.create_file(b"");
+        {
+            let writer = BufferedWriter::new(File::open_shared(&test.path).unwrap(), options).unwrap();
+
+            writer.write(b"written by the timer").unwrap();
+            wait_result(&|callback| writer.when_written(callback)).unwrap();
+            assert_eq!(&b"written by the timer"[..], &std::fs::read(&test.path).unwrap()[..]);
+
+            let (waiter, notifier) = create_waiter();
+            writer.close(Box::new(move |result| {
+                result.unwrap();
+                notifier.notify();
+            }));
+            waiter.wait();
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_at() {
+        let test = Test::with_path("test_read_at");
+        let data = Test::create_data(5000);
+
+        test.create_file(&data);
+        {
+            let file = File::open(&test.path).unwrap();
+            let read_at = |offset: u64, len: usize| {
+                wait_result(&|callback| {
+                    file.read_at(offset, len, Box::new(move |result| {
+                        callback(result.map(|read_data| read_data.to_vec()))
+                    }))
+                }).unwrap()
+            };
+
+            assert_eq!(&data[1000..3000], &read_at(1000, 2000)[..]);
+            assert_eq!(&data[4990..], &read_at(4990, 100)[..]);
+            assert!(read_at(6000, 100).is_empty());
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_at_sequential() {
+        let test = Test::with_path("test_read_at_sequential");
+        let data = Test::create_data(2048 * 10 + 42);
+        let mut options = ReadAheadOptions::new();
+
+        options.queue_depth = 2;
+        options.chunk_size = 4096;
+        options.window = 4096 * 3;
+        test.create_file(&data);
+        {
+            let mut file = File::open(&test.path).unwrap();
+
+            file.set_read_ahead(Some(options));
+            let read_at = |offset: u64, len: usize| {
+                wait_result(&|callback| {
+                    file.read_at(offset, len, Box::new(move |result| {
+                        callback(result.map(|read_data| read_data.to_vec()))
+                    }))
+                }).unwrap()
+            };
+
+            // Served from the chunks read ahead once the reads are sequential,
+            // including across chunks and at the end of the file.
+            let mut offset = 0;
+            while offset < data.len() {
+                let len = if offset == 0 { 100 } else { 1000 };
+                let end = std::cmp::min(offset + len, data.len());
+
+                assert_eq!(&data[offset..end], &read_at(offset as u64, len)[..]);
+                offset += len;
+            }
+            assert!(read_at(offset as u64, 100).is_empty());
+            assert_eq!(&data[42..142], &read_at(42, 100)[..]);
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_coalesce() {
+        let test = Test::with_path("test_coalesce");
+        let data = Test::create_data(1024 * 16);
+        let mut options = CoalesceOptions::new();
+
+        options.max_in_flight = 1;
+        options.max_request_size = 1024 * 8;
+        {
+            let file = std::cell::RefCell::new(File::create(&test.path).unwrap());
+            let (waiter, notifier) = create_waiter();
+            let notifier = std::sync::Arc::new(notifier);
+            let nb_done = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let nb_requests = 16;
+
+            file.borrow_mut().set_coalescing(Some(options));
+            for (index, chunk) in data.chunks(1024).enumerate().rev() {
+                let nb_done = nb_done.clone();
+                let notifier = notifier.clone();
+
+                file.borrow().write_at(index as u64 * 1024, chunk.to_vec(), Box::new(move |result| {
+                    result.unwrap();
+                    if nb_done.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1 == nb_requests {
+                        notifier.notify();
+                    }
+                }));
+            }
+            waiter.wait();
+
+            let (waiter, notifier) = create_waiter();
+            let notifier = std::sync::Arc::new(notifier);
+            let nb_done = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            for offset in (0..data.len()).step_by(500) {
+                let expected_data = data[offset..std::cmp::min(offset + 700, data.len())].to_vec();
+                let nb_done = nb_done.clone();
+                let notifier = notifier.clone();
+                let nb_reads = (data.len() + 499) / 500;
+
+                file.borrow().read_at(offset as u64, 700, Box::new(move |result| {
+                    assert_eq!(expected_data, result.unwrap());
+                    if nb_done.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1 == nb_reads {
+                        notifier.notify();
+                    }
+                }));
+            }
+            waiter.wait();
+        }
+        assert_eq!(data, std::fs::read(&test.path).unwrap());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_scheduler() {
+        let mut options = SchedulerOptions::new();
+
+        options.max_in_flight = 1;
+        options.latency.weight = 4;
+        options.bulk.weight = 1;
+        let scheduler = Scheduler::new(options);
+        let started = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+
+        for &priority in [Priority::Bulk, Priority::Bulk, Priority::Bulk,
+                          Priority::Latency, Priority::Latency, Priority::Latency,
+                          Priority::Latency, Priority::Latency].iter() {
+            let started = started.clone();
+
+            scheduler.submit(priority, 1024, Box::new(move || started.borrow_mut().push(priority)));
+        }
+        assert_eq!(vec![Priority::Bulk], *started.borrow());
+
+        for _ in 0..7 {
+            let last = *started.borrow().last().unwrap();
+            scheduler.complete(last);
+        }
+        // Once both classes are queued, the latency class gets 4 times the
+        // share of the bulk class.
+        assert_eq!(vec![Priority::Bulk, Priority::Latency, Priority::Bulk,
+                        Priority::Latency, Priority::Latency, Priority::Latency,
+                        Priority::Latency, Priority::Bulk],
+                   *started.borrow());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_at_with_priority() {
+        let test = Test::with_path("test_read_at_with_priority");
+        let data = Test::create_data(4096);
+
+        test.create_file(&data);
+        {
+            let file = File::open(&test.path).unwrap();
+
+            for &priority in [Priority::Latency, Priority::Normal, Priority::Bulk].iter() {
+                let read_data = wait_result(&|callback| {
+                    file.read_at_with_priority(1000, 2000, priority, Box::new(move |result| {
+                        callback(result.map(|read_data| read_data.to_vec()))
+                    }))
+                }).unwrap();
+                assert_eq!(&data[1000..3000], &read_data[..]);
+            }
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_rate_limiter() {
+        let mut limits = RateLimits::new();
+
+        limits.write_bytes_per_second = Some(100000);
+        let limiter = RateLimiter::new(limits);
+        let (waiter, notifier) = create_waiter();
+        let notifier = std::sync::Arc::new(notifier);
+        let nb_started = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        for _ in 0..4 {
+            let nb_started = nb_started.clone();
+            let notifier = notifier.clone();
+
+            limiter.acquire(IoKind::Write, 50000, Box::new(move || {
+                if nb_started.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1 == 4 {
+                    notifier.notify();
+                }
+            }));
+        }
+        // The bucket holds one second of writes, the reads are not limited.
+        assert_eq!(2, nb_started.load(std::sync::atomic::Ordering::SeqCst));
+        limiter.acquire(IoKind::Read, 50000, Box::new(|| {}));
+
+        let start = std::time::Instant::now();
+        limiter.set_limits(RateLimits::new());
+        waiter.wait();
+        assert!(start.elapsed() < std::time::Duration::from_millis(400));
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_at_rate_limited() {
+        let test = Test::with_path("test_read_at_rate_limited");
+        let data = Test::create_data(4096);
+        let mut limits = RateLimits::new();
+
+        limits.read_ops_per_second = Some(10);
+        test.create_file(&data);
+        {
+            let file = std::cell::RefCell::new(File::open(&test.path).unwrap());
+            let start = std::time::Instant::now();
+
+            file.borrow_mut().set_rate_limiter(Some(RateLimiter::new(limits)));
+            for _ in 0..15 {
+                let read_data = wait_result(&|callback| {
+                    file.borrow().read_at(0, 100, Box::new(move |result| {
+                        callback(result.map(|read_data| read_data.to_vec()))
+                    }))
+                }).unwrap();
+                assert_eq!(&data[..100], &read_data[..]);
+            }
+            // 10 reads in the bucket, then one every 100ms.
+            assert!(start.elapsed() >= std::time::Duration::from_millis(400));
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_write_all_rate_limited() {
+        let test = Test::with_path("test_write_all_rate_limited");
+        let cluster_size = File::get_cluster_size();
+        let data = Test::create_data(4 * cluster_size);
+        let mut options = StripedWriteOptions::new();
+        let mut limits = RateLimits::new();
+
+        options.stripe_size = cluster_size;
+        limits.write_bytes_per_second = Some(2 * cluster_size as u64);
+        {
+            let file = std::cell::RefCell::new(File::create(&test.path).unwrap());
+            let start = std::time::Instant::now();
+
+            file.borrow_mut().set_striped_write(options);
+            file.borrow_mut().set_rate_limiter(Some(RateLimiter::new(limits)));
+            wait_result(&|callback| file.borrow_mut().write_all(data.clone(), callback)).unwrap();
+            // Charged stripe by stripe: 2 stripes in the bucket, then one
+            // every 500ms.
+            assert!(start.elapsed() >= std::time::Duration::from_millis(900));
+        }
+        test.check_read(data);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_in_flight_limiter() {
+        let mut limits = InFlightLimits::new();
+
+        limits.max_operations = Some(1);
+        let limiter = InFlightLimiter::new(limits);
+        let permits = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        for _ in 0..3 {
+            let permits = permits.clone();
+
+            InFlightLimiter::acquire(&limiter, 10, Box::new(move |permit| {
+                permits.lock().unwrap().push(permit.unwrap());
+            }));
+        }
+        // The others wait for the first permit to be dropped.
+        assert_eq!(1, permits.lock().unwrap().len());
+        assert_eq!((1, 10), limiter.in_flight());
+
+        let permit = permits.lock().unwrap().pop().unwrap();
+        drop(permit);
+        assert_eq!(1, permits.lock().unwrap().len());
+
+        limits.max_operations = Some(2);
+        InFlightLimiter::set_limits(&limiter, limits);
+        assert_eq!(2, permits.lock().unwrap().len());
+        assert_eq!((2, 20), limiter.in_flight());
+
+        limits.mode = BackpressureMode::Fail;
+        InFlightLimiter::set_limits(&limiter, limits);
+        InFlightLimiter::acquire(&limiter, 10, Box::new(|permit| {
+            assert!(permit.is_err());
+        }));
+
+        permits.lock().unwrap().clear();
+        assert_eq!((0, 0), limiter.in_flight());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_read_at_in_flight_limited() {
+        let test = Test::with_path("test_read_at_in_flight_limited");
+        let data = Test::create_data(4096);
+        let mut limits = InFlightLimits::new();
+
+        limits.max_bytes = Some(1000);
+        limits.mode = BackpressureMode::Fail;
+        test.create_file(&data);
+        {
+            let limiter = InFlightLimiter::new(limits);
+            let file = std::cell::RefCell::new(File::open(&test.path).unwrap());
+            let permit = std::sync::Arc::new(std::sync::Mutex::new(None));
+            let permit_clone = permit.clone();
+
+            file.borrow_mut().set_in_flight_limiter(Some(limiter.clone()));
+            InFlightLimiter::acquire(&limiter, 500, Box::new(move |result| {
+                *permit_clone.lock().unwrap() = Some(result.unwrap());
+            }));
+
+            let result = wait_result(&|callback| {
+                file.borrow().read_at(0, 1000, Box::new(move |result| {
+                    callback(result.map(|read_data| read_data.to_vec()))
+                }))
+            });
+            assert!(result.unwrap_err().starts_with("Error would block"));
+
+            *permit.lock().unwrap() = None;
+            let read_data = wait_result(&|callback| {
+                file.borrow().read_at(0, 1000, Box::new(move |result| {
+                    callback(result.map(|read_data| read_data.to_vec()))
+                }))
+            }).unwrap();
+            assert_eq!(&data[..1000], &read_data[..]);
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_drop_with_queued_operations() {
+        let test = Test::with_path("test_drop_with_queued_operations");
+        let data = Test::create_data(4096);
+        let mut limits = InFlightLimits::new();
+
+        limits.max_operations = Some(1);
+        test.create_file(&data);
+        {
+            let limiter = InFlightLimiter::new(limits);
+            let mut file = File::open(&test.path).unwrap();
+            let permit = std::sync::Arc::new(std::sync::Mutex::new(None));
+            let permit_clone = permit.clone();
+            let (waiter, notifier) = create_waiter();
+            let notifier = std::sync::Arc::new(notifier);
+            let errors = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let (write_notifier, write_errors) = (notifier.clone(), errors.clone());
+            let (read_notifier, read_errors) = (notifier.clone(), errors.clone());
+
+            file.set_in_flight_limiter(Some(limiter.clone()));
+            InFlightLimiter::acquire(&limiter, 0, Box::new(move |result| {
+                *permit_clone.lock().unwrap() = Some(result.unwrap());
+            }));
+            file.write_all(vec![7; 100], Box::new(move |result| {
+                let mut errors = write_errors.lock().unwrap();
+
+                errors.push(result.unwrap_err());
+                if errors.len() == 2 {
+                    write_notifier.notify();
+                }
+            }));
+            file.read_all(Box::new(move |result| {
+                let mut errors = read_errors.lock().unwrap();
+
+                errors.push(result.unwrap_err());
+                if errors.len() == 2 {
+                    read_notifier.notify();
+                }
+            }));
+            assert_eq!((1, 0), limiter.in_flight());
+
+            // The queued operations start once the File is dropped.
+            drop(file);
+            *permit.lock().unwrap() = None;
+            waiter.wait();
+            for error in errors.lock().unwrap().iter() {
+                assert_eq!("Error the file is dropped.", error);
+            }
+        }
+        assert_eq!(data, std::fs::read(&test.path).unwrap());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_drop_with_queued_write_at() {
+        let test = Test::with_path("test_drop_with_queued_write_at");
+        let cluster_size = File::get_cluster_size();
+        let data = Test::create_data(4 * cluster_size);
+        let mut limits = RateLimits::new();
+
+        limits.write_ops_per_second = Some(1);
+        test.create_file(&data);
+        {
+            let mut file = File::open(&test.path).unwrap();
+            let (waiter, notifier) = create_waiter();
+            let notifier = std::sync::Arc::new(notifier);
+            let results = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+            file.set_rate_limiter(Some(RateLimiter::new(limits)));
+            // The second write waits in the coalescer for the rate limiter.
+            for &offset in [0, 2 * cluster_size as u64].iter() {
+                let results = results.clone();
+                let notifier = notifier.clone();
+
+                file.write_at(offset, vec![7; cluster_size], Box::new(move |result| {
+                    let mut results = results.lock().unwrap();
+
+                    results.push((offset, result));
+                    if results.len() == 2 {
+                        notifier.notify();
+                    }
+                }));
+            }
+            drop(file);
+            waiter.wait();
+
+            let mut results = results.lock().unwrap();
+            results.sort_by_key(|&(offset, _)| offset);
+            assert_eq!(Ok(()), results[0].1);
+            assert_eq!(Err("Error the file is dropped.".to_string()), results[1].1);
+        }
+
+        let mut expected_data = data.clone();
+        for value in &mut expected_data[..cluster_size] {
+            *value = 7;
+        }
+        assert_eq!(expected_data, std::fs::read(&test.path).unwrap());
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_ordered() {
+        let test = Test::with_path("test_ordered");
+        let cluster_size = File::get_cluster_size();
+        let data = Test::create_data(4 * cluster_size);
+
+        test.create_file(&data);
+        {
+            let mut file = File::open(&test.path).unwrap();
+            let (waiter, notifier) = create_waiter();
+            let notifier = std::sync::Arc::new(notifier);
+            let completed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let read_data = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+            assert!(wait_result(&|callback| file.write_barrier(callback)).is_err());
+            file.set_ordered(true);
+            for index in 0..8 {
+                let completed = completed.clone();
+
+                file.write_at((index % 2 * cluster_size) as u64,
+                              vec![index as u8; cluster_size],
+                              Box::new(move |result| {
+                                  result.unwrap();
+                                  completed.lock().unwrap().push(index);
+                              }));
+            }
+            {
+                let completed = completed.clone();
+
+                file.write_barrier(Box::new(move |result| {
+                    result.unwrap();
+                    completed.lock().unwrap().push(8);
+                }));
+            }
+            {
+                let completed = completed.clone();
+                let read_data = read_data.clone();
+                let notifier = notifier.clone();
+
+                file.read_at(0, 2 * cluster_size, Box::new(move |result| {
+                    *read_data.lock().unwrap() = result.unwrap().to_vec();
+                    completed.lock().unwrap().push(9);
+                    notifier.notify();
+                }));
+            }
+            waiter.wait();
+
+            let read_data = read_data.lock().unwrap();
+            assert_eq!((0..10).collect::<Vec<_>>(), *completed.lock().unwrap());
+            assert_eq!(&vec![6; cluster_size][..], &read_data[..cluster_size]);
+            assert_eq!(&vec![7; cluster_size][..], &read_data[cluster_size..]);
+        }
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_path_with_spaces() {
+        let test = Test::with_path("test with spaces");
+
+        test.test_write_all(42);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_path_with_unicode() {
+        let test = Test::with_path("test_\u{e9}\u{4e2d}\u{1F600}");
+
+        test.test_write_all(42);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_path_with_unpaired_surrogate() {
+        let name: Vec<u16> = "test_".encode_utf16().chain(Some(0xD800)).collect();
+        let test = Test::with_path(OsString::from_wide(&name));
+
+        test.test_write_all(42);
+    }
+
+    // -----------------------------------------------------------------------------
+    #[test]
+    fn it_test_long_path() {
+        let root = Path::new("test_long_path");
+        let name: String = std::iter::repeat('d').take(100).collect();
+        let dir = root.join(&name).join(&name).join(&name);
+
+        std::fs::create_dir_all(&dir).unwrap();
+        {
+            let test = Test::with_path(dir.join("test"));
+
+            test.test_write_all(42);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
     // -----------------------------------------------------------------------------
     #[test]
     fn it_test_add_usize_to_u32_pair() {
@@ -246,8 +1661,6 @@ mod test {
 } 
 
 // $$ TODO
-// $$$ test special chars
 // $$ Improve error handling
 // $$ create a write buffer to be able to reuse the buffer
-// $$ Implement the right logic for File::Drop
-// $$ use rigth value for read_all
\ No newline at end of file
+// $$ Implement the right logic for File::Drop
\ No newline at end of file
@@ -0,0 +1,114 @@
+use win_api_helper::get_file_information_by_handle_ex;
+
+use winapi::HANDLE;
+use winapi::FILE_BASIC_INFO;
+use winapi::FILE_STANDARD_INFO;
+use winapi::FileBasicInfo;
+use winapi::FileStandardInfo;
+use winapi::FILE_ATTRIBUTE_READONLY;
+use winapi::FILE_ATTRIBUTE_REPARSE_POINT;
+
+use std::time::SystemTime;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+// -----------------------------------------------------------------------------
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    len: u64,
+    allocation_size: u64,
+    modified: SystemTime,
+    accessed: SystemTime,
+    created: SystemTime,
+    readonly: bool,
+    file_type: FileType,
+}
+
+// -----------------------------------------------------------------------------
+impl Metadata {
+    // -------------------------------------------------------------------------
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    // -------------------------------------------------------------------------
+    // Space reserved on the disk, at least len rounded to the cluster size
+    // unless the file is sparse or compressed.
+    pub fn allocation_size(&self) -> u64 {
+        self.allocation_size
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn accessed(&self) -> SystemTime {
+        self.accessed
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+}
+
+// -----------------------------------------------------------------------------
+pub(crate) fn query_metadata(file: HANDLE) -> Result<Metadata, String> {
+    let basic_info: FILE_BASIC_INFO =
+        try!(get_file_information_by_handle_ex(file, FileBasicInfo));
+    let standard_info: FILE_STANDARD_INFO =
+        try!(get_file_information_by_handle_ex(file, FileStandardInfo));
+    let file_type = if basic_info.FileAttributes & FILE_ATTRIBUTE_REPARSE_POINT != 0 {
+        FileType::Symlink
+    } else if standard_info.Directory != 0 {
+        FileType::Directory
+    } else {
+        FileType::File
+    };
+
+    Ok(Metadata {
+        len: standard_info.EndOfFile as u64,
+        allocation_size: standard_info.AllocationSize as u64,
+        modified: file_time_to_system_time(basic_info.LastWriteTime),
+        accessed: file_time_to_system_time(basic_info.LastAccessTime),
+        created: file_time_to_system_time(basic_info.CreationTime),
+        readonly: basic_info.FileAttributes & FILE_ATTRIBUTE_READONLY != 0,
+        file_type: file_type,
+    })
+}
+
+// -----------------------------------------------------------------------------
+// File times count 100ns intervals since 1601-01-01.
+fn file_time_to_system_time(file_time: i64) -> SystemTime {
+    const INTERVALS_PER_SECOND: i64 = 10_000_000;
+    const INTERVALS_TO_UNIX_EPOCH: i64 = 11_644_473_600 * INTERVALS_PER_SECOND;
+    let intervals = file_time - INTERVALS_TO_UNIX_EPOCH;
+    let duration = Duration::new((intervals.abs() / INTERVALS_PER_SECOND) as u64,
+                                 ((intervals.abs() % INTERVALS_PER_SECOND) * 100) as u32);
+
+    if intervals >= 0 {
+        UNIX_EPOCH + duration
+    } else {
+        UNIX_EPOCH - duration
+    }
+}
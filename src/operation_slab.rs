@@ -0,0 +1,417 @@
+use async_data::AsyncData;
+
+use winapi::LPOVERLAPPED;
+use winapi::OVERLAPPED;
+
+use std::mem::forget;
+use std::mem::replace;
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::ONCE_INIT;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::ptr::null;
+
+// Each shard has its own lock, the operations are spread over them in turn.
+const NB_SHARDS: usize = 16;
+
+// -----------------------------------------------------------------------------
+// Index of an operation in the slab: its slot times NB_SHARDS plus its shard.
+pub type Token = usize;
+
+// The completion port only gives back the OVERLAPPED address: the AsyncData
+// holding it is found from there as CONTAINING_RECORD does, and its token is
+// read. The operation itself is only reached through the slab.
+const OVERLAPPED_OFFSET: usize = ::std::mem::offset_of!(AsyncData, overlapped);
+const TOKEN_OFFSET: usize = ::std::mem::offset_of!(AsyncData, token);
+
+// The token is not part of the OVERLAPPED the OS writes to.
+const _: () = assert!(TOKEN_OFFSET + size_of::<Token>() <= OVERLAPPED_OFFSET ||
+                      OVERLAPPED_OFFSET + size_of::<OVERLAPPED>() <= TOKEN_OFFSET);
+
+// -----------------------------------------------------------------------------
+enum Slot<T> {
+    Occupied(T),
+    // Next free slot.
+    Vacant(Option<usize>),
+}
+
+// -----------------------------------------------------------------------------
+struct Shard<T> {
+    slots: Vec<Slot<T>>,
+    first_free: Option<usize>,
+    len: usize,
+}
+
+// -----------------------------------------------------------------------------
+impl<T> Shard<T> {
+    // -------------------------------------------------------------------------
+    // Takes a free slot, left vacant until the caller fills it.
+    fn insert_placeholder(&mut self) -> usize {
+        let index = match self.first_free {
+            Some(index) => {
+                if let Slot::Vacant(next_free) = self.slots[index] {
+                    self.first_free = next_free;
+                }
+                index
+            }
+            None => {
+                self.slots.push(Slot::Vacant(None));
+                self.slots.len() - 1
+            }
+        };
+
+        self.len += 1;
+        index
+    }
+
+    // -------------------------------------------------------------------------
+    // Frees a slot taken by insert_placeholder and never filled.
+    fn release_placeholder(&mut self, index: usize) {
+        self.slots[index] = Slot::Vacant(self.first_free);
+        self.first_free = Some(index);
+        self.len -= 1;
+    }
+
+    // -------------------------------------------------------------------------
+    fn remove(&mut self, index: usize) -> Option<T> {
+        match self.slots.get(index) {
+            Some(&Slot::Occupied(_)) => {}
+            _ => return None,
+        }
+
+        let slot = replace(&mut self.slots[index], Slot::Vacant(self.first_free));
+
+        self.first_free = Some(index);
+        self.len -= 1;
+        match slot {
+            Slot::Occupied(value) => Some(value),
+            Slot::Vacant(_) => None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// A slot taken while its value is created, given back if the creation panics.
+struct Placeholder<'a, T: 'a> {
+    shard: &'a Mutex<Shard<T>>,
+    index: usize,
+}
+
+// -----------------------------------------------------------------------------
+impl<'a, T> Placeholder<'a, T> {
+    // -------------------------------------------------------------------------
+    fn fill(self, value: T) {
+        self.shard.lock().unwrap().slots[self.index] = Slot::Occupied(value);
+        forget(self);
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl<'a, T> Drop for Placeholder<'a, T> {
+    fn drop(&mut self) {
+        if let Ok(mut shard) = self.shard.lock() {
+            shard.release_placeholder(self.index);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Values indexed by the token returned when they are inserted.
+pub struct Slab<T> {
+    shards: Vec<Mutex<Shard<T>>>,
+    next_shard: AtomicUsize,
+}
+
+// -----------------------------------------------------------------------------
+impl<T> Slab<T> {
+    // -------------------------------------------------------------------------
+    pub fn new() -> Slab<T> {
+        Slab {
+            shards: (0..NB_SHARDS).map(|_| {
+                Mutex::new(Shard { slots: Vec::new(), first_free: None, len: 0 })
+            }).collect(),
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Create receives the token of the value before it is stored. It is called
+    // without the shard locked: if it panics the slot is freed and the shard
+    // is not poisoned.
+    pub fn insert_with<F: FnOnce(Token) -> T>(&self, create: F) -> Token {
+        let shard_index = self.next_shard.fetch_add(1, Ordering::Relaxed) % NB_SHARDS;
+        let shard = &self.shards[shard_index];
+        let index = shard.lock().unwrap().insert_placeholder();
+        let token = index * NB_SHARDS + shard_index;
+        let placeholder = Placeholder { shard: shard, index: index };
+
+        placeholder.fill(create(token));
+        token
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn remove(&self, token: Token) -> Option<T> {
+        self.shards[token % NB_SHARDS].lock().unwrap().remove(token / NB_SHARDS)
+    }
+
+    // -------------------------------------------------------------------------
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len).sum()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Owns the in-flight operations while the OS works on their buffers. The
+// boxes never move while they are in the slab, so the OVERLAPPED handed to the
+// OS stays valid until the operation is removed.
+pub struct OperationSlab {
+    operations: Slab<Box<AsyncData>>,
+}
+
+static INIT_OPERATION_SLAB: Once = ONCE_INIT;
+static mut OPERATION_SLAB: *const OperationSlab = null();
+
+// -----------------------------------------------------------------------------
+pub fn operation_slab() -> &'static OperationSlab {
+    unsafe {
+        INIT_OPERATION_SLAB.call_once(|| {
+            OPERATION_SLAB = Box::into_raw(Box::new(OperationSlab::new()));
+        });
+        &*OPERATION_SLAB
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl OperationSlab {
+    // -------------------------------------------------------------------------
+    pub fn new() -> OperationSlab {
+        OperationSlab { operations: Slab::new() }
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn insert(&self, mut async_data: Box<AsyncData>) -> (Token, LPOVERLAPPED) {
+        // Taken from a pointer to the whole AsyncData, token_of goes back to it.
+        let record = &mut *async_data as *mut AsyncData;
+        let overlapped = unsafe { ::std::ptr::addr_of_mut!((*record).overlapped) };
+        let token = self.operations.insert_with(move |token| {
+            async_data.token = token;
+            async_data
+        });
+
+        (token, overlapped)
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn remove(&self, token: Token) -> Option<Box<AsyncData>> {
+        self.operations.remove(token)
+    }
+
+    // -------------------------------------------------------------------------
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// The overlapped must come from OperationSlab::insert and the operation must
+// not be removed yet.
+pub unsafe fn token_of(overlapped: LPOVERLAPPED) -> Token {
+    let record = (overlapped as *const u8).offset(-(OVERLAPPED_OFFSET as isize)) as
+                 *const AsyncData;
+
+    *::std::ptr::addr_of!((*record).token)
+}
+
+// -----------------------------------------------------------------------------
+// These tests do not touch the OS: the operations are inserted and removed as
+// the workers would, without being handed to it.
+#[cfg(test)]
+mod test {
+    use super::OperationSlab;
+    use super::Slab;
+    use super::NB_SHARDS;
+    use super::operation_slab;
+    use super::token_of;
+    use async_data::AsyncData;
+    use io_worker::complete_operation;
+    use win_api_helper::CompletionStatus;
+    use std::cell::RefCell;
+    use std::panic::catch_unwind;
+    use std::panic::AssertUnwindSafe;
+    use std::ptr::null_mut;
+    use std::rc::Rc;
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_slab_insert_remove() {
+        let marker = Rc::new(());
+        let slab = Slab::new();
+        let token = slab.insert_with(|_| marker.clone());
+
+        assert_eq!(1, slab.len());
+        assert!(slab.remove(token).is_some());
+        assert!(slab.remove(token).is_none());
+        assert_eq!(0, slab.len());
+        assert_eq!(1, Rc::strong_count(&marker));
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_slab_receives_its_token() {
+        let slab = Slab::new();
+        let tokens: Vec<_> = (0..3 * NB_SHARDS).map(|_| slab.insert_with(|token| token)).collect();
+
+        for token in tokens {
+            assert_eq!(Some(token), slab.remove(token));
+        }
+        assert_eq!(0, slab.len());
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_slab_reuses_slots() {
+        let slab = Slab::new();
+        let tokens: Vec<_> = (0..NB_SHARDS).map(|index| slab.insert_with(|_| index)).collect();
+
+        // The next insert goes to the first shard again.
+        assert_eq!(Some(0), slab.remove(tokens[0]));
+        let reused = slab.insert_with(|_| 42);
+
+        assert_eq!(tokens[0], reused);
+        assert_eq!(Some(42), slab.remove(reused));
+        assert_eq!(NB_SHARDS - 1, slab.len());
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_slab_drops_pending_values() {
+        let marker = Rc::new(());
+        {
+            let slab = Slab::new();
+
+            slab.insert_with(|_| marker.clone());
+            slab.insert_with(|_| marker.clone());
+            assert_eq!(3, Rc::strong_count(&marker));
+        }
+        assert_eq!(1, Rc::strong_count(&marker));
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_slab_unknown_token() {
+        let slab: Slab<usize> = Slab::new();
+
+        assert!(slab.remove(0).is_none());
+        assert!(slab.remove(12345).is_none());
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_slab_frees_the_slot_when_create_panics() {
+        let slab = Slab::new();
+        let inserted = catch_unwind(AssertUnwindSafe(|| {
+            slab.insert_with(|_| -> usize { panic!("create failed") })
+        }));
+
+        assert!(inserted.is_err());
+        assert_eq!(0, slab.len());
+
+        // The next insert in the first shard gets its slot back.
+        let tokens: Vec<_> = (0..NB_SHARDS).map(|_| slab.insert_with(|token| token)).collect();
+
+        assert_eq!(0, tokens[NB_SHARDS - 1]);
+        assert_eq!(NB_SHARDS, slab.len());
+    }
+
+    // -------------------------------------------------------------------------
+    fn create_async_data(results: &Rc<RefCell<Vec<usize>>>) -> Box<AsyncData> {
+        let results = results.clone();
+
+        Box::new(AsyncData::new_read_into_data(null_mut(), 4096, Box::new(move |result| {
+            results.borrow_mut().push(result.unwrap_or(0));
+        })))
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_operation_slab_insert_remove() {
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let slab = OperationSlab::new();
+        let (token, overlapped) = slab.insert(create_async_data(&results));
+
+        assert_eq!(1, slab.len());
+        assert_eq!(token, unsafe { token_of(overlapped) });
+
+        let async_data = slab.remove(token).unwrap();
+
+        assert_eq!(overlapped as *const _, &async_data.overlapped as *const _);
+        assert_eq!(token, async_data.token);
+        assert_eq!(4096, async_data.overlapped.Offset);
+        assert!(slab.remove(token).is_none());
+        assert_eq!(0, slab.len());
+        assert!(results.borrow().is_empty());
+    }
+
+    // -------------------------------------------------------------------------
+    // The operation is submitted to the slab of the workers and completed
+    // from the OVERLAPPED alone, as with a packet of the completion port.
+    #[test]
+    fn it_test_operation_slab_round_trip() {
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let (token, overlapped) = operation_slab().insert(create_async_data(&results));
+
+        complete_operation(CompletionStatus {
+            nb_bytes_transferred: 100,
+            overlapped: overlapped,
+            end_of_file: false,
+            error: None,
+        });
+        assert_eq!(vec![100], *results.borrow());
+        assert!(operation_slab().remove(token).is_none());
+
+        let (_, overlapped) = operation_slab().insert(create_async_data(&results));
+
+        complete_operation(CompletionStatus {
+            nb_bytes_transferred: 0,
+            overlapped: overlapped,
+            end_of_file: false,
+            error: Some("Error cancelled".to_string()),
+        });
+        assert_eq!(vec![100, 0], *results.borrow());
+        assert_eq!(1, Rc::strong_count(&results));
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_operation_slab_several_operations() {
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let slab = OperationSlab::new();
+        let inserted: Vec<_> = (0..3).map(|_| slab.insert(create_async_data(&results))).collect();
+
+        for &(token, overlapped) in inserted.iter().rev() {
+            assert_eq!(token, unsafe { token_of(overlapped) });
+            assert_eq!(token, slab.remove(token).unwrap().token);
+        }
+        assert_eq!(0, slab.len());
+        assert_eq!(1, Rc::strong_count(&results));
+    }
+
+    // -------------------------------------------------------------------------
+    #[test]
+    fn it_test_operation_slab_drops_pending_operations() {
+        let results = Rc::new(RefCell::new(Vec::new()));
+        {
+            let slab = OperationSlab::new();
+
+            slab.insert(create_async_data(&results));
+            slab.insert(create_async_data(&results));
+            assert_eq!(3, Rc::strong_count(&results));
+        }
+        assert_eq!(1, Rc::strong_count(&results));
+        assert!(results.borrow().is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use tools::take_locked;
+
+use std::collections::BTreeMap;
+use std::mem::replace;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// -----------------------------------------------------------------------------
+type WriteCallback = Box<Fn(Result<(), String>)>;
+type ReadCallback = Box<Fn(Result<&[u8], String>)>;
+
+// -----------------------------------------------------------------------------
+// Range of the file an operation reads or writes, end excluded.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Access {
+    Read(u64, u64),
+    Write(u64, u64),
+    // Waits for everything before it, everything after it waits for it.
+    Barrier,
+}
+
+// -----------------------------------------------------------------------------
+impl Access {
+    // -------------------------------------------------------------------------
+    fn conflicts_with(&self, other: &Access) -> bool {
+        match (*self, *other) {
+            (Access::Barrier, _) | (_, Access::Barrier) => true,
+            (Access::Read(..), Access::Read(..)) => false,
+            (Access::Read(start, end), Access::Write(other_start, other_end)) |
+            (Access::Write(start, end), Access::Read(other_start, other_end)) |
+            (Access::Write(start, end), Access::Write(other_start, other_end)) => {
+                start < other_end && other_start < end
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+enum Stage {
+    // Start of the operation, called with its id.
+    Waiting(Box<Fn(u64)>),
+    InFlight,
+    // The operation is applied, its callback is called once the ones before
+    // it are.
+    Done(Box<Fn()>),
+}
+
+// -----------------------------------------------------------------------------
+struct Entry {
+    access: Access,
+    stage: Stage,
+}
+
+// -----------------------------------------------------------------------------
+struct SequencerState {
+    next_id: u64,
+    // By submission order, until their callback is called.
+    entries: BTreeMap<u64, Entry>,
+    // A single thread calls the callbacks at a time, in order.
+    delivering: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Orders the operations of a file. An operation starts once the ones before
+// it overlapping its range are applied, and is called back after all the ones
+// before it.
+pub(crate) struct Sequencer {
+    state: Mutex<SequencerState>,
+}
+
+// -----------------------------------------------------------------------------
+impl Sequencer {
+    // -------------------------------------------------------------------------
+    pub(crate) fn new() -> Arc<Sequencer> {
+        Arc::new(Sequencer {
+            state: Mutex::new(SequencerState {
+                next_id: 0,
+                entries: BTreeMap::new(),
+                delivering: false,
+            }),
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // Start must end with a call to complete with the id it is given.
+    pub(crate) fn submit(sequencer: &Arc<Sequencer>, access: Access, start: Box<Fn(u64)>) {
+        {
+            let mut state = sequencer.state.lock().unwrap();
+            let id = state.next_id;
+
+            state.next_id += 1;
+            state.entries.insert(id, Entry { access: access, stage: Stage::Waiting(start) });
+        }
+        schedule(sequencer);
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn complete(sequencer: &Arc<Sequencer>, id: u64, deliver: Box<Fn()>) {
+        {
+            let mut state = sequencer.state.lock().unwrap();
+
+            if let Some(entry) = state.entries.get_mut(&id) {
+                entry.stage = Stage::Done(deliver);
+            }
+        }
+        schedule(sequencer);
+        deliver_in_order(sequencer);
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn schedule(sequencer: &Arc<Sequencer>) {
+    let starts = take_locked(&sequencer.state, |state| {
+        let mut starts = Vec::new();
+        let mut not_applied: Vec<Access> = Vec::new();
+
+        for (&id, entry) in state.entries.iter_mut() {
+            let can_start = match entry.stage {
+                Stage::Done(_) => continue,
+                Stage::InFlight => false,
+                Stage::Waiting(_) => !not_applied.iter().any(|access| access.conflicts_with(&entry.access)),
+            };
+
+            if can_start {
+                if let Stage::Waiting(start) = replace(&mut entry.stage, Stage::InFlight) {
+                    starts.push((id, start));
+                }
+            }
+            not_applied.push(entry.access);
+        }
+        starts
+    });
+
+    for (id, start) in starts {
+        start.as_ref()(id);
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn deliver_in_order(sequencer: &Arc<Sequencer>) {
+    {
+        let mut state = sequencer.state.lock().unwrap();
+
+        if state.delivering {
+            return;
+        }
+        state.delivering = true;
+    }
+    loop {
+        let deliver = {
+            let mut state = sequencer.state.lock().unwrap();
+            let first_done = match state.entries.iter().next() {
+                Some((&id, &Entry { stage: Stage::Done(_), .. })) => Some(id),
+                _ => None,
+            };
+
+            match first_done.and_then(|id| state.entries.remove(&id)) {
+                Some(Entry { stage: Stage::Done(deliver), .. }) => deliver,
+                _ => {
+                    state.delivering = false;
+                    return;
+                }
+            }
+        };
+
+        deliver.as_ref()();
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Issue starts the write with the callback it is given. Without sequencer it is
+// called right away.
+pub(crate) fn ordered_write(sequencer: Option<Arc<Sequencer>>,
+                            access: Access,
+                            issue: Box<Fn(WriteCallback)>)
+                            -> Box<Fn(WriteCallback)> {
+    let sequencer = match sequencer {
+        Some(sequencer) => sequencer,
+        None => return issue,
+    };
+    let issue = Arc::new(issue);
+
+    Box::new(move |callback| {
+        let callback = Arc::new(callback);
+        let sequencer_done = sequencer.clone();
+        let issue = issue.clone();
+
+        Sequencer::submit(&sequencer, access, Box::new(move |id| {
+            let sequencer_done = sequencer_done.clone();
+            let callback = callback.clone();
+
+            issue.as_ref()(Box::new(move |result| {
+                let callback = callback.clone();
+
+                Sequencer::complete(&sequencer_done, id, Box::new(move || {
+                    callback.as_ref()(result.clone())
+                }));
+            }));
+        }));
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Same as ordered_write, the data read is copied to be given to the callback
+// later.
+pub(crate) fn ordered_read(sequencer: Option<Arc<Sequencer>>,
+                           access: Access,
+                           issue: Box<Fn(ReadCallback)>)
+                           -> Box<Fn(ReadCallback)> {
+    let sequencer = match sequencer {
+        Some(sequencer) => sequencer,
+        None => return issue,
+    };
+    let issue = Arc::new(issue);
+
+    Box::new(move |callback| {
+        let callback = Arc::new(callback);
+        let sequencer_done = sequencer.clone();
+        let issue = issue.clone();
+
+        Sequencer::submit(&sequencer, access, Box::new(move |id| {
+            let sequencer_done = sequencer_done.clone();
+            let callback = callback.clone();
+
+            issue.as_ref()(Box::new(move |result| {
+                let callback = callback.clone();
+                let result = result.map(|data| data.to_vec());
+
+                Sequencer::complete(&sequencer_done, id, Box::new(move || {
+                    callback.as_ref()(result.as_ref().map(|data| &data[..]).map_err(|error| error.clone()))
+                }));
+            }));
+        }));
+    })
+}
@@ -0,0 +1,225 @@
+use async_data::AsyncData;
+
+use tools::post_task;
+use tools::read_file_async_data_buffer;
+use tools::take_locked;
+
+use rate_limit::IoKind;
+
+use scheduler::Throttle;
+
+use sparse::allocated_ranges;
+use sparse::query_allocated_extents;
+
+use win_api_helper::get_file_size_ex;
+
+use winapi::HANDLE;
+
+use std::cmp::max;
+use std::cmp::min;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct ParallelReadOptions {
+    // Maximum number of chunk reads in flight.
+    pub parallelism: usize,
+    // Size of each read, rounded up to the cluster size.
+    pub chunk_size: usize,
+}
+
+// -----------------------------------------------------------------------------
+impl ParallelReadOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> ParallelReadOptions {
+        ParallelReadOptions {
+            parallelism: 8,
+            chunk_size: 4 << 20,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Chunk reads in flight: starts at window, doubled each time a chunk comes
+// back full up to max_window.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ChunkWindow {
+    pub window: usize,
+    pub max_window: usize,
+}
+
+// -----------------------------------------------------------------------------
+struct ChunkedReadState {
+    // Allocated once to the file size rounded up to the cluster size: the
+    // chunks are read in place and it must not move until the reads are done.
+    // The holes are left to zero.
+    data: Vec<u8>,
+    // Allocated ranges not read yet.
+    ranges: VecDeque<(u64, u64)>,
+    window: usize,
+    nb_reading: usize,
+    // Reduced by the short reads if the file shrinks while it is read.
+    len: usize,
+    error: Option<String>,
+    done: bool,
+}
+
+// -----------------------------------------------------------------------------
+struct ChunkedRead {
+    file: HANDLE,
+    chunk_size: usize,
+    max_window: usize,
+    throttle: Throttle,
+    state: Mutex<ChunkedReadState>,
+    callback: Box<Fn(Result<&[u8], String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// Reads the file as long as it is when the read starts, with several chunks
+// read at the same time into a single buffer.
+pub(crate) fn read_all_parallel(file: HANDLE,
+                                options: &ParallelReadOptions,
+                                cluster_size: usize,
+                                throttle: Throttle,
+                                callback: Box<Fn(Result<&[u8], String>)>) {
+    let parallelism = max(1, options.parallelism);
+    let window = ChunkWindow { window: parallelism, max_window: parallelism };
+
+    read_allocated(file, options.chunk_size, window, cluster_size, throttle, callback)
+}
+
+// -----------------------------------------------------------------------------
+// The file length and its allocated extents are queried on an io worker, then
+// only the extents are read: the holes of a sparse file are not read. Each
+// chunk goes through the throttle.
+pub(crate) fn read_allocated(file: HANDLE,
+                             chunk_size: usize,
+                             window: ChunkWindow,
+                             cluster_size: usize,
+                             throttle: Throttle,
+                             callback: Box<Fn(Result<&[u8], String>)>) {
+    let round_up = move |size: usize| ((size + cluster_size - 1) / cluster_size) * cluster_size;
+    let callback = RefCell::new(Some(callback));
+
+    post_task(Box::new(move |result| {
+        let callback = callback.borrow_mut().take().expect("read_allocated started twice");
+        let query = result.
+            and_then(|_| get_file_size_ex(file)).
+            and_then(|len| query_allocated_extents(file).map(|extents| (len as usize, extents)));
+        let (len, extents) = match query {
+            Ok(query) => query,
+            Err(error) => return callback.as_ref()(Err(error)),
+        };
+        let chunked_read = Arc::new(ChunkedRead {
+            file: file,
+            chunk_size: max(cluster_size, round_up(chunk_size)),
+            max_window: max(1, window.max_window),
+            throttle: throttle.clone(),
+            state: Mutex::new(ChunkedReadState {
+                data: vec![0; round_up(len)],
+                ranges: allocated_ranges(&extents, cluster_size, round_up(len) as u64).
+                    into_iter().
+                    collect(),
+                window: max(1, min(window.window, window.max_window)),
+                nb_reading: 0,
+                len: len,
+                error: None,
+                done: false,
+            }),
+            callback: callback,
+        });
+
+        schedule(&chunked_read);
+    }));
+}
+
+// -----------------------------------------------------------------------------
+fn schedule(chunked_read: &Arc<ChunkedRead>) {
+    let (chunks, result) = take_locked(&chunked_read.state, |state| {
+        let mut chunks = Vec::new();
+
+        while state.error.is_none() && state.nb_reading < state.window {
+            let (offset, end) = match state.ranges.pop_front() {
+                Some(range) => range,
+                None => break,
+            };
+            let size = min(chunked_read.chunk_size as u64, end - offset) as usize;
+
+            if offset + (size as u64) < end {
+                state.ranges.push_front((offset + size as u64, end));
+            }
+            chunks.push((offset, state.data[offset as usize..].as_mut_ptr(), size));
+            state.nb_reading += 1;
+        }
+        if state.nb_reading == 0 && !state.done {
+            state.done = true;
+            (chunks, Some(match state.error.take() {
+                Some(error) => Err(error),
+                None => {
+                    let mut data = ::std::mem::replace(&mut state.data, Vec::new());
+
+                    data.truncate(state.len);
+                    Ok(data)
+                }
+            }))
+        } else {
+            (chunks, None)
+        }
+    });
+
+    for (offset, buffer, size) in chunks {
+        read_chunk(chunked_read, offset, buffer, size);
+    }
+    match result {
+        Some(Ok(data)) => (chunked_read.callback)(Ok(&data)),
+        Some(Err(error)) => (chunked_read.callback)(Err(error)),
+        None => {}
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn read_chunk(chunked_read: &Arc<ChunkedRead>, offset: u64, buffer: *mut u8, size: usize) {
+    let chunked_read_chunk = chunked_read.clone();
+
+    chunked_read.throttle.submit(IoKind::Read, size, Box::new(move |request| {
+        let chunked_read = chunked_read_chunk.clone();
+        let async_data = Box::new(AsyncData::new_read_into_data(
+            chunked_read.file,
+            offset,
+            Box::new(move |result| {
+                let _request = &request;
+
+                chunked_read.complete_chunk(offset, size, result);
+                schedule(&chunked_read);
+            })));
+
+        read_file_async_data_buffer(chunked_read_chunk.file, buffer, size, async_data);
+    }));
+}
+
+// -----------------------------------------------------------------------------
+impl ChunkedRead {
+    // -------------------------------------------------------------------------
+    fn complete_chunk(&self, offset: u64, size: usize, result: Result<usize, String>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.nb_reading -= 1;
+        match result {
+            Ok(nb_bytes_read) if nb_bytes_read < size => {
+                let len = min(state.len, offset as usize + nb_bytes_read);
+
+                state.len = len;
+                state.ranges.retain(|&(start, _)| start < len as u64);
+            }
+            Ok(_) => state.window = min(state.window * 2, self.max_window),
+            Err(error) => {
+                if state.error.is_none() {
+                    state.error = Some(error);
+                }
+            }
+        }
+    }
+}
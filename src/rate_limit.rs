@@ -0,0 +1,345 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::ptr::null;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::ONCE_INIT;
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+// The limiter thread checks at this interval whether the limiter is dropped.
+const MAX_WAIT_MS: u64 = 100;
+
+// -----------------------------------------------------------------------------
+// None is no limit. The buckets hold one second of each rate, which is the
+// largest burst allowed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub read_bytes_per_second: Option<u64>,
+    pub read_ops_per_second: Option<u64>,
+    pub write_bytes_per_second: Option<u64>,
+    pub write_ops_per_second: Option<u64>,
+}
+
+// -----------------------------------------------------------------------------
+impl RateLimits {
+    // -------------------------------------------------------------------------
+    pub fn new() -> RateLimits {
+        RateLimits::default()
+    }
+}
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum IoKind {
+    Read,
+    Write,
+}
+
+// -----------------------------------------------------------------------------
+struct Bucket {
+    rate: Option<u64>,
+    // Negative after a request larger than the bucket.
+    tokens: f64,
+}
+
+// -----------------------------------------------------------------------------
+impl Bucket {
+    // -------------------------------------------------------------------------
+    fn new(rate: Option<u64>) -> Bucket {
+        Bucket {
+            rate: rate,
+            tokens: rate.unwrap_or(0) as f64,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn set_rate(&mut self, rate: Option<u64>) {
+        if let Some(rate) = rate {
+            self.tokens = self.tokens.min(rate as f64);
+        }
+        self.rate = rate;
+    }
+
+    // -------------------------------------------------------------------------
+    fn refill(&mut self, elapsed: f64) {
+        if let Some(rate) = self.rate {
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Time until a request of this cost can go, a request larger than the
+    // bucket goes once it is full.
+    fn wait_time(&self, cost: u64) -> Option<Duration> {
+        match self.rate {
+            Some(rate) => {
+                let missing = (cost.min(rate) as f64 - self.tokens).max(0.0);
+
+                if missing == 0.0 {
+                    None
+                } else {
+                    let nanos = (missing / rate.max(1) as f64 * 1e9).ceil() as u64;
+
+                    Some(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32))
+                }
+            }
+            None => None,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn take(&mut self, cost: u64) {
+        if self.rate.is_some() {
+            self.tokens -= cost as f64;
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct Direction {
+    bytes: Bucket,
+    ops: Bucket,
+    // Requests waiting for tokens, in submission order.
+    queue: VecDeque<(usize, Box<Fn()>)>,
+}
+
+// -----------------------------------------------------------------------------
+impl Direction {
+    // -------------------------------------------------------------------------
+    fn new(bytes_per_second: Option<u64>, ops_per_second: Option<u64>) -> Direction {
+        Direction {
+            bytes: Bucket::new(bytes_per_second),
+            ops: Bucket::new(ops_per_second),
+            queue: VecDeque::new(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn wait_time(&self, cost: usize) -> Option<Duration> {
+        match (self.bytes.wait_time(cost as u64), self.ops.wait_time(1)) {
+            (Some(bytes_wait), Some(ops_wait)) => Some(bytes_wait.max(ops_wait)),
+            (bytes_wait, ops_wait) => bytes_wait.or(ops_wait),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn take(&mut self, cost: usize) {
+        self.bytes.take(cost as u64);
+        self.ops.take(1);
+    }
+
+    // -------------------------------------------------------------------------
+    // The queued requests which can go, and the time until the next one can.
+    fn release(&mut self, starts: &mut Vec<Box<Fn()>>) -> Option<Duration> {
+        loop {
+            let wait_time = match self.queue.front() {
+                Some(&(cost, _)) => self.wait_time(cost),
+                None => return None,
+            };
+
+            match wait_time {
+                Some(wait_time) => return Some(wait_time),
+                None => {
+                    let (cost, start) = self.queue.pop_front().unwrap();
+
+                    self.take(cost);
+                    starts.push(start);
+                }
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct LimiterState {
+    limits: RateLimits,
+    last_refill: Instant,
+    read: Direction,
+    write: Direction,
+}
+
+// -----------------------------------------------------------------------------
+impl LimiterState {
+    // -------------------------------------------------------------------------
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+        self.last_refill = now;
+        self.read.bytes.refill(elapsed);
+        self.read.ops.refill(elapsed);
+        self.write.bytes.refill(elapsed);
+        self.write.ops.refill(elapsed);
+    }
+
+    // -------------------------------------------------------------------------
+    fn direction(&mut self, kind: IoKind) -> &mut Direction {
+        match kind {
+            IoKind::Read => &mut self.read,
+            IoKind::Write => &mut self.write,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Token buckets limiting the reads and the writes of the files it is attached
+// to. The requests over the limits are delayed, in order.
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+    changed: Condvar,
+}
+
+// -----------------------------------------------------------------------------
+// Lets the limiter thread release the queued requests. RateLimiter is not
+// Send only because its queues hold Box<Fn()>: the starts built by
+// acquire_rate. They are only pushed and popped with the state locked, and
+// each one is called once, after it is popped. A start submits an operation
+// to the completion port, or calls its callback right away if it cannot: both
+// may be done from any thread, the starts released by a completion already
+// run on an io worker rather than on the thread which queued them.
+struct ThreadLimiter(Weak<RateLimiter>);
+
+unsafe impl Send for ThreadLimiter {}
+
+// -----------------------------------------------------------------------------
+impl RateLimiter {
+    // -------------------------------------------------------------------------
+    pub fn new(limits: RateLimits) -> Arc<RateLimiter> {
+        let limiter = Arc::new(RateLimiter {
+            state: Mutex::new(LimiterState {
+                limits: limits,
+                last_refill: Instant::now(),
+                read: Direction::new(limits.read_bytes_per_second, limits.read_ops_per_second),
+                write: Direction::new(limits.write_bytes_per_second, limits.write_ops_per_second),
+            }),
+            changed: Condvar::new(),
+        });
+        let thread_limiter = ThreadLimiter(Arc::downgrade(&limiter));
+
+        thread::spawn(move || release_periodically(thread_limiter));
+        limiter
+    }
+
+    // -------------------------------------------------------------------------
+    pub fn limits(&self) -> RateLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    // -------------------------------------------------------------------------
+    // Applies to the queued requests too.
+    pub fn set_limits(&self, limits: RateLimits) {
+        let mut state = self.state.lock().unwrap();
+
+        state.refill();
+        state.limits = limits;
+        state.read.bytes.set_rate(limits.read_bytes_per_second);
+        state.read.ops.set_rate(limits.read_ops_per_second);
+        state.write.bytes.set_rate(limits.write_bytes_per_second);
+        state.write.ops.set_rate(limits.write_ops_per_second);
+        self.changed.notify_all();
+    }
+
+    // -------------------------------------------------------------------------
+    // Start is called right away if the request is within the limits, from the
+    // thread of the limiter later otherwise.
+    pub(crate) fn acquire(&self, kind: IoKind, cost: usize, start: Box<Fn()>) {
+        {
+            let mut state = self.state.lock().unwrap();
+
+            state.refill();
+            let direction = state.direction(kind);
+            if !direction.queue.is_empty() || direction.wait_time(cost).is_some() {
+                direction.queue.push_back((cost, start));
+                self.changed.notify_all();
+                return;
+            }
+            direction.take(cost);
+        }
+        start.as_ref()();
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Stops once the RateLimiter is dropped.
+fn release_periodically(thread_limiter: ThreadLimiter) {
+    loop {
+        let limiter = match thread_limiter.0.upgrade() {
+            Some(limiter) => limiter,
+            None => return,
+        };
+        let mut starts = Vec::new();
+        {
+            let mut state = limiter.state.lock().unwrap();
+
+            state.refill();
+            let read_wait = state.read.release(&mut starts);
+            let write_wait = state.write.release(&mut starts);
+            let max_wait = Duration::from_millis(MAX_WAIT_MS);
+            let wait = min(read_wait.unwrap_or(max_wait), write_wait.unwrap_or(max_wait));
+
+            if starts.is_empty() {
+                let _ = limiter.changed.wait_timeout(state, min(wait, max_wait)).unwrap();
+            }
+        }
+        // As with take_locked, but the thread waits on the guard when nothing
+        // is released.
+        for start in starts {
+            start.as_ref()();
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+static INIT_GLOBAL_RATE_LIMITER: Once = ONCE_INIT;
+static mut GLOBAL_RATE_LIMITER: *const Mutex<Option<Arc<RateLimiter>>> = null();
+
+// -----------------------------------------------------------------------------
+fn global_rate_limiter() -> &'static Mutex<Option<Arc<RateLimiter>>> {
+    unsafe {
+        INIT_GLOBAL_RATE_LIMITER.call_once(|| {
+            GLOBAL_RATE_LIMITER = Box::into_raw(Box::new(Mutex::new(None)));
+        });
+        &*GLOBAL_RATE_LIMITER
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Limits the requests of all the files, on top of their own limiter.
+pub fn set_global_rate_limiter(limiter: Option<Arc<RateLimiter>>) {
+    *global_rate_limiter().lock().unwrap() = limiter;
+}
+
+// -----------------------------------------------------------------------------
+// Goes through the limiter of the file, then the global one.
+pub(crate) fn acquire_rate(file_limiter: Option<Arc<RateLimiter>>,
+                           kind: IoKind,
+                           cost: usize,
+                           start: Box<Fn()>) {
+    let mut limiters: Vec<Arc<RateLimiter>> = file_limiter.into_iter().collect();
+
+    limiters.extend(global_rate_limiter().lock().unwrap().clone());
+    acquire_all(Arc::new(limiters), 0, kind, cost, Arc::new(start));
+}
+
+// -----------------------------------------------------------------------------
+fn acquire_all(limiters: Arc<Vec<Arc<RateLimiter>>>,
+               index: usize,
+               kind: IoKind,
+               cost: usize,
+               start: Arc<Box<Fn()>>) {
+    if index == limiters.len() {
+        return start.as_ref()();
+    }
+
+    let next_limiters = limiters.clone();
+
+    limiters[index].acquire(kind, cost, Box::new(move || {
+        acquire_all(next_limiters.clone(), index + 1, kind, cost, start.clone())
+    }));
+}
@@ -0,0 +1,268 @@
+use coalesce::Coalescer;
+
+use parallel_read::ChunkWindow;
+use parallel_read::read_allocated;
+
+use scheduler::Priority;
+use scheduler::Throttle;
+
+use tools::take_locked;
+
+use winapi::HANDLE;
+
+use std::cmp::max;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Consecutive read_at calls, each starting where the previous one ended,
+// before the next chunks are read ahead.
+const SEQUENTIAL_THRESHOLD: usize = 2;
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct ReadAheadOptions {
+    // Maximum number of chunk reads in flight.
+    pub queue_depth: usize,
+    // Size of each read, rounded up to the cluster size.
+    pub chunk_size: usize,
+    // Bytes read ahead of a sequential reader, and at most in flight for
+    // read_all.
+    pub window: usize,
+}
+
+// -----------------------------------------------------------------------------
+impl ReadAheadOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> ReadAheadOptions {
+        ReadAheadOptions {
+            queue_depth: 8,
+            chunk_size: 1 << 20,
+            window: 8 << 20,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Reads the whole file with a window of chunk reads in flight, starting at one
+// and doubled each time a chunk comes back full, up to the queue depth and the
+// window.
+pub(crate) fn read_all_ahead(file: HANDLE,
+                             options: &ReadAheadOptions,
+                             cluster_size: usize,
+                             throttle: Throttle,
+                             callback: Box<Fn(Result<&[u8], String>)>) {
+    let max_window = min(options.queue_depth, options.window / max(1, options.chunk_size));
+    let window = ChunkWindow { window: 1, max_window: max(1, max_window) };
+
+    read_allocated(file, options.chunk_size, window, cluster_size, throttle, callback)
+}
+
+// -----------------------------------------------------------------------------
+struct WaitingRead {
+    offset: u64,
+    len: usize,
+    callback: Box<Fn(Result<&[u8], String>)>,
+}
+
+// -----------------------------------------------------------------------------
+enum Chunk {
+    // The read_at calls served once the chunk is read.
+    Reading(Vec<WaitingRead>),
+    Read(Vec<u8>),
+}
+
+// -----------------------------------------------------------------------------
+struct ReadAheadState {
+    // Where the next read_at starts if the reads are sequential.
+    next_offset: u64,
+    nb_sequential: usize,
+    nb_reading: usize,
+    // Found by a short chunk read, nothing is read ahead past it.
+    end_of_file: Option<u64>,
+    // By offset, a multiple of the chunk size.
+    chunks: BTreeMap<u64, Chunk>,
+}
+
+// -----------------------------------------------------------------------------
+// Detects the sequential read_at calls of a file and reads the next chunks
+// ahead of them. The reads which lie within a chunk are served from it.
+pub(crate) struct ReadAhead {
+    pub(crate) options: ReadAheadOptions,
+    chunk_size: u64,
+    state: Mutex<ReadAheadState>,
+}
+
+// -----------------------------------------------------------------------------
+impl ReadAhead {
+    // -------------------------------------------------------------------------
+    pub(crate) fn new(options: ReadAheadOptions, cluster_size: usize) -> Arc<ReadAhead> {
+        let chunk_size = max(1, (options.chunk_size + cluster_size - 1) / cluster_size) *
+                         cluster_size;
+
+        Arc::new(ReadAhead {
+            options: options,
+            chunk_size: chunk_size as u64,
+            state: Mutex::new(ReadAheadState {
+                next_offset: 0,
+                nb_sequential: 0,
+                nb_reading: 0,
+                end_of_file: None,
+                chunks: BTreeMap::new(),
+            }),
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // The chunks are read through the coalescer with the bulk priority, the
+    // reads which are not served from a chunk go through it as usual.
+    pub(crate) fn read_at(read_ahead: &Arc<ReadAhead>,
+                          coalescer: &Arc<Coalescer>,
+                          priority: Priority,
+                          offset: u64,
+                          len: usize,
+                          callback: Box<Fn(Result<&[u8], String>)>) {
+        let mut callback = Some(callback);
+        let mut served = None;
+        let chunks_to_read = take_locked(&read_ahead.state, |state| {
+            let chunk_start = offset - offset % read_ahead.chunk_size;
+            let end = offset + len as u64;
+
+            if offset == state.next_offset {
+                state.nb_sequential += 1;
+            } else {
+                state.nb_sequential = 0;
+                state.chunks.retain(|_, chunk| match *chunk {
+                    Chunk::Reading(ref reads) => !reads.is_empty(),
+                    Chunk::Read(_) => false,
+                });
+            }
+            state.next_offset = end;
+            // The chunks behind the reader are not read again.
+            state.chunks.retain(|&start, chunk| match *chunk {
+                Chunk::Reading(_) => true,
+                Chunk::Read(_) => start + read_ahead.chunk_size > offset,
+            });
+
+            if end <= chunk_start + read_ahead.chunk_size {
+                match state.chunks.get_mut(&chunk_start) {
+                    Some(&mut Chunk::Reading(ref mut reads)) => {
+                        reads.push(WaitingRead {
+                            offset: offset,
+                            len: len,
+                            callback: callback.take().unwrap(),
+                        })
+                    }
+                    Some(&mut Chunk::Read(ref data)) => {
+                        served = Some(chunk_slice(data, chunk_start, offset, len).to_vec())
+                    }
+                    None => {}
+                }
+            }
+            if state.nb_sequential >= SEQUENTIAL_THRESHOLD {
+                read_ahead.chunks_ahead(state)
+            } else {
+                Vec::new()
+            }
+        });
+
+        for start in chunks_to_read {
+            read_chunk(read_ahead, coalescer, start);
+        }
+        match (callback, served) {
+            (Some(callback), Some(data)) => callback.as_ref()(Ok(&data)),
+            (Some(callback), None) => Coalescer::read_at(coalescer, priority, offset, len, callback),
+            (None, _) => {}
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Forgets the chunks read ahead over a range which is modified. The chunks
+    // still being read are only dropped if no read waits for them.
+    pub(crate) fn invalidate(&self, offset: u64, end: u64) {
+        let chunk_size = self.chunk_size;
+        let mut state = self.state.lock().unwrap();
+
+        state.end_of_file = None;
+        state.chunks.retain(|&start, chunk| {
+            start + chunk_size <= offset || start >= end ||
+            match *chunk {
+                Chunk::Reading(ref reads) => !reads.is_empty(),
+                Chunk::Read(_) => false,
+            }
+        });
+    }
+
+    // -------------------------------------------------------------------------
+    // The chunks of the window after the reader which are not read yet, within
+    // the queue depth.
+    fn chunks_ahead(&self, state: &mut ReadAheadState) -> Vec<u64> {
+        let mut chunks_to_read = Vec::new();
+        let mut start = state.next_offset - state.next_offset % self.chunk_size;
+        let end = state.next_offset + self.options.window as u64;
+        let end = state.end_of_file.map_or(end, |end_of_file| min(end, end_of_file));
+
+        while start < end && state.nb_reading < max(1, self.options.queue_depth) {
+            if !state.chunks.contains_key(&start) {
+                state.chunks.insert(start, Chunk::Reading(Vec::new()));
+                state.nb_reading += 1;
+                chunks_to_read.push(start);
+            }
+            start += self.chunk_size;
+        }
+        chunks_to_read
+    }
+
+    // -------------------------------------------------------------------------
+    fn complete_chunk(&self, start: u64, result: Result<&[u8], String>) {
+        let reads = {
+            let mut state = self.state.lock().unwrap();
+
+            state.nb_reading -= 1;
+            if let Ok(data) = result {
+                if (data.len() as u64) < self.chunk_size {
+                    state.end_of_file = Some(start + data.len() as u64);
+                }
+            }
+            match state.chunks.remove(&start) {
+                Some(Chunk::Reading(reads)) => {
+                    if let Ok(data) = result {
+                        state.chunks.insert(start, Chunk::Read(data.to_vec()));
+                    }
+                    reads
+                }
+                Some(chunk) => {
+                    state.chunks.insert(start, chunk);
+                    Vec::new()
+                }
+                None => Vec::new(),
+            }
+        };
+
+        for read in reads {
+            read.callback.as_ref()(result.clone().map(|data| {
+                chunk_slice(data, start, read.offset, read.len)
+            }));
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn read_chunk(read_ahead: &Arc<ReadAhead>, coalescer: &Arc<Coalescer>, start: u64) {
+    let read_ahead_chunk = read_ahead.clone();
+
+    Coalescer::read_at(coalescer,
+                       Priority::Bulk,
+                       start,
+                       read_ahead.chunk_size as usize,
+                       Box::new(move |result| read_ahead_chunk.complete_chunk(start, result)));
+}
+
+// -----------------------------------------------------------------------------
+// Less data past the end of the file.
+fn chunk_slice(data: &[u8], chunk_start: u64, offset: u64, len: usize) -> &[u8] {
+    let start = min((offset - chunk_start) as usize, data.len());
+
+    &data[start..min(start + len, data.len())]
+}
@@ -0,0 +1,279 @@
+use tools::take_locked;
+
+use rate_limit::IoKind;
+use rate_limit::RateLimiter;
+use rate_limit::acquire_rate;
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::ptr::null;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::ONCE_INIT;
+
+// Virtual time of a byte for a weight of 1.
+const VIRTUAL_TIME_SCALE: u64 = 1 << 16;
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // Lookups waited on by a user.
+    Latency,
+    Normal,
+    // Background work such as compactions.
+    Bulk,
+}
+
+// -----------------------------------------------------------------------------
+impl Priority {
+    // -------------------------------------------------------------------------
+    fn index(self) -> usize {
+        match self {
+            Priority::Latency => 0,
+            Priority::Normal => 1,
+            Priority::Bulk => 2,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct ClassOptions {
+    pub max_in_flight: usize,
+    // Share of the bandwidth when several classes have queued requests.
+    pub weight: u32,
+}
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct SchedulerOptions {
+    // Requests in flight for all the classes, shared by weight between the
+    // classes with queued requests.
+    pub max_in_flight: usize,
+    pub latency: ClassOptions,
+    pub normal: ClassOptions,
+    pub bulk: ClassOptions,
+}
+
+// -----------------------------------------------------------------------------
+impl SchedulerOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> SchedulerOptions {
+        SchedulerOptions {
+            max_in_flight: 64,
+            latency: ClassOptions { max_in_flight: 64, weight: 16 },
+            normal: ClassOptions { max_in_flight: 32, weight: 4 },
+            bulk: ClassOptions { max_in_flight: 4, weight: 1 },
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn classes(&self) -> [ClassOptions; 3] {
+        [self.latency, self.normal, self.bulk]
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct Class {
+    options: ClassOptions,
+    // Cost in bytes and start of the queued requests.
+    queue: VecDeque<(usize, Box<Fn()>)>,
+    nb_in_flight: usize,
+    // Grows with the bytes sent divided by the weight: the class with the
+    // smallest one is served first.
+    virtual_time: u64,
+}
+
+// -----------------------------------------------------------------------------
+impl Class {
+    // -------------------------------------------------------------------------
+    fn is_busy(&self) -> bool {
+        !self.queue.is_empty() || self.nb_in_flight > 0
+    }
+
+    // -------------------------------------------------------------------------
+    fn can_start(&self) -> bool {
+        !self.queue.is_empty() && self.nb_in_flight < self.options.max_in_flight
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct SchedulerState {
+    max_in_flight: usize,
+    nb_in_flight: usize,
+    classes: Vec<Class>,
+}
+
+// -----------------------------------------------------------------------------
+// Weighted fair queuing of the requests of all the files between the priority
+// classes, in front of the completion port.
+pub struct Scheduler {
+    state: Mutex<SchedulerState>,
+}
+
+static INIT_SCHEDULER: Once = ONCE_INIT;
+static mut SCHEDULER: *const Scheduler = null();
+
+// -----------------------------------------------------------------------------
+pub(crate) fn scheduler() -> &'static Scheduler {
+    unsafe {
+        INIT_SCHEDULER.call_once(|| {
+            SCHEDULER = Box::into_raw(Box::new(Scheduler::new(SchedulerOptions::new())));
+        });
+        &*SCHEDULER
+    }
+}
+
+// -----------------------------------------------------------------------------
+// The new limits apply to the requests not started yet.
+pub fn set_scheduler_options(options: SchedulerOptions) {
+    scheduler().set_options(options)
+}
+
+// -----------------------------------------------------------------------------
+impl Scheduler {
+    // -------------------------------------------------------------------------
+    pub(crate) fn new(options: SchedulerOptions) -> Scheduler {
+        Scheduler {
+            state: Mutex::new(SchedulerState {
+                max_in_flight: options.max_in_flight,
+                nb_in_flight: 0,
+                classes: options.classes().iter().map(|&class_options| {
+                    Class {
+                        options: class_options,
+                        queue: VecDeque::new(),
+                        nb_in_flight: 0,
+                        virtual_time: 0,
+                    }
+                }).collect(),
+            }),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn set_options(&self, options: SchedulerOptions) {
+        {
+            let mut state = self.state.lock().unwrap();
+
+            state.max_in_flight = options.max_in_flight;
+            for (class, &class_options) in state.classes.iter_mut().zip(options.classes().iter()) {
+                class.options = class_options;
+            }
+        }
+        self.start_requests();
+    }
+
+    // -------------------------------------------------------------------------
+    // Start is called once the request may be sent, complete must be called
+    // when it is done.
+    pub(crate) fn submit(&self, priority: Priority, cost: usize, start: Box<Fn()>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let classes = &mut state.classes;
+            let index = priority.index();
+
+            // An idle class does not keep the credit of the time it was idle.
+            if !classes[index].is_busy() {
+                let busy_virtual_time = classes.iter().
+                    filter(|class| class.is_busy()).
+                    map(|class| class.virtual_time).
+                    min();
+
+                if let Some(virtual_time) = busy_virtual_time {
+                    classes[index].virtual_time = max(classes[index].virtual_time, virtual_time);
+                }
+            }
+            classes[index].queue.push_back((cost, start));
+        }
+        self.start_requests();
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn complete(&self, priority: Priority) {
+        {
+            let mut state = self.state.lock().unwrap();
+
+            state.nb_in_flight -= 1;
+            state.classes[priority.index()].nb_in_flight -= 1;
+        }
+        self.start_requests();
+    }
+
+    // -------------------------------------------------------------------------
+    fn start_requests(&self) {
+        loop {
+            let start = take_locked(&self.state, |state| {
+                if state.nb_in_flight >= state.max_in_flight {
+                    return None;
+                }
+
+                let next_class = state.classes.iter_mut().
+                    filter(|class| class.can_start()).
+                    min_by_key(|class| class.virtual_time);
+
+                match next_class {
+                    Some(class) => {
+                        let (cost, start) = class.queue.pop_front().unwrap();
+                        let weight = max(1, class.options.weight) as u64;
+
+                        state.nb_in_flight += 1;
+                        class.nb_in_flight += 1;
+                        class.virtual_time += max(1, cost as u64) * VIRTUAL_TIME_SCALE / weight;
+                        Some(start)
+                    }
+                    None => None,
+                }
+            });
+
+            match start {
+                Some(start) => start.as_ref()(),
+                None => return,
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Completes its request when dropped with the callback of the operation, so
+// that an operation dropped without calling back does not hold its class.
+pub(crate) struct ScheduledRequest {
+    priority: Priority,
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for ScheduledRequest {
+    fn drop(&mut self) {
+        scheduler().complete(self.priority);
+    }
+}
+
+// -----------------------------------------------------------------------------
+// The priority and the rate limiter of a file. The requests of an operation
+// go through them one by one, so that a long operation keeps to the rates and
+// shares the disk rather than being charged at once.
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    pub(crate) priority: Priority,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+// -----------------------------------------------------------------------------
+impl Throttle {
+    // -------------------------------------------------------------------------
+    // The request waits for the rate limiters, then for the scheduler. Start
+    // receives the scheduled request, to be dropped once the request is done.
+    pub(crate) fn submit(&self, kind: IoKind, cost: usize, start: Box<Fn(ScheduledRequest)>) {
+        let priority = self.priority;
+        let start = RefCell::new(Some(start));
+
+        acquire_rate(self.rate_limiter.clone(), kind, cost, Box::new(move || {
+            let start = start.borrow_mut().take().expect("Request started twice");
+
+            scheduler().submit(priority, cost, Box::new(move || {
+                start.as_ref()(ScheduledRequest { priority: priority })
+            }));
+        }));
+    }
+}
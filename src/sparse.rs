@@ -0,0 +1,124 @@
+use win_api_helper::device_io_control;
+use win_api_helper::get_file_size_ex;
+
+use winapi::HANDLE;
+use winapi::LPVOID;
+use winapi::FSCTL_SET_SPARSE;
+use winapi::FSCTL_SET_ZERO_DATA;
+use winapi::FSCTL_QUERY_ALLOCATED_RANGES;
+
+use std::ptr::null_mut;
+use std::mem::size_of;
+use std::cmp::max;
+use std::cmp::min;
+
+// -----------------------------------------------------------------------------
+// Same layout as FILE_ZERO_DATA_INFORMATION and FILE_ALLOCATED_RANGE_BUFFER.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct FileRange {
+    offset: i64,
+    end_or_len: i64,
+}
+
+// -----------------------------------------------------------------------------
+// A range of the file which is allocated on the disk. Everything outside the
+// extents is a hole which reads as zeros.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+// -----------------------------------------------------------------------------
+pub(crate) fn set_sparse(file: HANDLE) -> Result<(), String> {
+    device_io_control(file, FSCTL_SET_SPARSE, null_mut(), 0, null_mut(), 0).map(|_| ())
+}
+
+// -----------------------------------------------------------------------------
+// Deallocates the range if the file is sparse, writes zeros otherwise.
+pub(crate) fn set_zero_data(file: HANDLE, offset: u64, len: u64) -> Result<(), String> {
+    let end = match offset.checked_add(len) {
+        Some(end) if end <= i64::max_value() as u64 => end,
+        _ => return Err(format!("Error zero data: the range of {} bytes at {} is too large.",
+                                len,
+                                offset)),
+    };
+    let mut zero_data = FileRange {
+        offset: offset as i64,
+        end_or_len: end as i64,
+    };
+
+    device_io_control(file,
+                      FSCTL_SET_ZERO_DATA,
+                      &mut zero_data as *mut FileRange as LPVOID,
+                      size_of::<FileRange>(),
+                      null_mut(),
+                      0).map(|_| ())
+}
+
+// -----------------------------------------------------------------------------
+pub(crate) fn query_allocated_extents(file: HANDLE) -> Result<Vec<Extent>, String> {
+    let file_size = try!(get_file_size_ex(file));
+    let mut extents: Vec<Extent> = Vec::new();
+    let mut ranges = vec![FileRange { offset: 0, end_or_len: 0 }; 64];
+    let mut query = FileRange {
+        offset: 0,
+        end_or_len: file_size as i64,
+    };
+
+    loop {
+        let (output_size, more_data) = try!(device_io_control(
+            file,
+            FSCTL_QUERY_ALLOCATED_RANGES,
+            &mut query as *mut FileRange as LPVOID,
+            size_of::<FileRange>(),
+            ranges.as_mut_ptr() as LPVOID,
+            ranges.len() * size_of::<FileRange>()));
+        let nb_ranges = output_size / size_of::<FileRange>();
+
+        extents.extend(ranges[..nb_ranges].iter().map(|range| {
+            Extent {
+                offset: range.offset as u64,
+                len: range.end_or_len as u64,
+            }
+        }));
+        match extents.last() {
+            Some(last) if more_data => {
+                let next_offset = last.offset + last.len;
+
+                query.end_or_len = file_size as i64 - next_offset as i64;
+                query.offset = next_offset as i64;
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// The ranges to read to get the first len bytes of the file, aligned to the
+// cluster size and sorted. What is between them is a hole which reads as
+// zeros.
+pub(crate) fn allocated_ranges(extents: &[Extent], cluster_size: usize, len: u64) -> Vec<(u64, u64)> {
+    let cluster_size = cluster_size as u64;
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+
+    for extent in extents {
+        let start = (extent.offset / cluster_size) * cluster_size;
+        let end = min(((extent.offset + extent.len + cluster_size - 1) / cluster_size) * cluster_size,
+                      len);
+
+        if start >= end {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(&mut (_, ref mut last_end)) if start <= *last_end => {
+                *last_end = max(*last_end, end);
+                continue;
+            }
+            _ => {}
+        }
+        ranges.push((start, end));
+    }
+    ranges
+}
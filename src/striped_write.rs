@@ -0,0 +1,179 @@
+use async_data::AsyncData;
+
+use file::set_file_len;
+
+use rate_limit::IoKind;
+
+use scheduler::Throttle;
+
+use tools::take_locked;
+use tools::write_file_async_data_buffer;
+
+use winapi::HANDLE;
+
+use std::cmp::max;
+use std::cmp::min;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::u32;
+
+// -----------------------------------------------------------------------------
+#[derive(Copy, Clone, Debug)]
+pub struct StripedWriteOptions {
+    // Maximum number of stripe writes in flight.
+    pub parallelism: usize,
+    // Size of each write, rounded up to the cluster size and kept under 4 GiB.
+    // Smaller buffers are written with a single write.
+    pub stripe_size: usize,
+}
+
+// -----------------------------------------------------------------------------
+impl StripedWriteOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> StripedWriteOptions {
+        StripedWriteOptions {
+            parallelism: 8,
+            stripe_size: 4 << 20,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    pub(crate) fn aligned_stripe_size(&self, cluster_size: usize) -> usize {
+        let max_stripe_size = (u32::MAX as usize / cluster_size) * cluster_size;
+        let stripe_size = ((self.stripe_size + cluster_size - 1) / cluster_size) * cluster_size;
+
+        max(cluster_size, min(stripe_size, max_stripe_size))
+    }
+}
+
+// -----------------------------------------------------------------------------
+struct StripedWriteState {
+    next_offset: usize,
+    nb_writing: usize,
+    error: Option<String>,
+    done: bool,
+}
+
+// -----------------------------------------------------------------------------
+struct StripedWrite {
+    file: HANDLE,
+    // Padded to the cluster size, it must not move until the writes are done.
+    buffer: Vec<u8>,
+    bytes_to_write: usize,
+    parallelism: usize,
+    stripe_size: usize,
+    throttle: Throttle,
+    state: Mutex<StripedWriteState>,
+    callback: Box<Fn(Result<(), String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// Writes the padded buffer from the start of the file with several stripes in
+// flight, each one through the throttle. Once they all succeeded the file is
+// cut to bytes_to_write, the callback is called once with the first error
+// otherwise.
+pub(crate) fn write_all_striped(file: HANDLE,
+                                buffer: Vec<u8>,
+                                bytes_to_write: usize,
+                                options: &StripedWriteOptions,
+                                cluster_size: usize,
+                                throttle: Throttle,
+                                callback: Box<Fn(Result<(), String>)>) {
+    let striped_write = Arc::new(StripedWrite {
+        file: file,
+        buffer: buffer,
+        bytes_to_write: bytes_to_write,
+        parallelism: max(1, options.parallelism),
+        stripe_size: options.aligned_stripe_size(cluster_size),
+        throttle: throttle,
+        state: Mutex::new(StripedWriteState {
+            next_offset: 0,
+            nb_writing: 0,
+            error: None,
+            done: false,
+        }),
+        callback: callback,
+    });
+
+    schedule(&striped_write);
+}
+
+// -----------------------------------------------------------------------------
+fn schedule(striped_write: &Arc<StripedWrite>) {
+    let (stripes, result) = take_locked(&striped_write.state, |state| {
+        let mut stripes = Vec::new();
+
+        while state.error.is_none() && state.nb_writing < striped_write.parallelism &&
+              state.next_offset < striped_write.buffer.len() {
+            let offset = state.next_offset;
+            let size = min(striped_write.stripe_size, striped_write.buffer.len() - offset);
+
+            stripes.push((offset, size));
+            state.next_offset += size;
+            state.nb_writing += 1;
+        }
+        if state.nb_writing == 0 && !state.done {
+            state.done = true;
+            (stripes, Some(state.error.take()))
+        } else {
+            (stripes, None)
+        }
+    });
+
+    for (offset, size) in stripes {
+        write_stripe(striped_write, offset, size);
+    }
+    match result {
+        Some(Some(error)) => (striped_write.callback)(Err(error)),
+        Some(None) => {
+            (striped_write.callback)(set_file_len(striped_write.file,
+                                                  striped_write.bytes_to_write as u64))
+        }
+        None => {}
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn write_stripe(striped_write: &Arc<StripedWrite>, offset: usize, size: usize) {
+    let striped_write_stripe = striped_write.clone();
+
+    striped_write.throttle.submit(IoKind::Write, size, Box::new(move |request| {
+        let striped_write = striped_write_stripe.clone();
+        let async_data = Box::new(AsyncData::new_write_from_data(
+            striped_write.file,
+            offset as u64,
+            Box::new(move |result| {
+                let _request = &request;
+
+                striped_write.complete_stripe(size, result);
+                schedule(&striped_write);
+            })));
+
+        write_file_async_data_buffer(striped_write_stripe.file,
+                                     striped_write_stripe.buffer[offset..].as_ptr(),
+                                     size,
+                                     async_data);
+    }));
+}
+
+// -----------------------------------------------------------------------------
+impl StripedWrite {
+    // -------------------------------------------------------------------------
+    fn complete_stripe(&self, size: usize, result: Result<usize, String>) {
+        let mut state = self.state.lock().unwrap();
+        let result = result.and_then(|nb_bytes_written| if nb_bytes_written < size {
+            Err(format!("Error short write: {} bytes written instead of {}.",
+                        nb_bytes_written,
+                        size))
+        } else {
+            Ok(())
+        });
+
+        state.nb_writing -= 1;
+        if let Err(error) = result {
+            if state.error.is_none() {
+                state.error = Some(error);
+            }
+        }
+    }
+}
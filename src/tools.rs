@@ -1,18 +1,40 @@
 use win_api_helper::write_file_async;
 use win_api_helper::read_file_async;
+use win_api_helper::lock_file_async;
+use win_api_helper::post_queued_completion_status;
 
-use win_api_helper::AsyncOperationError;
+use io_worker::init_static_completion_port_once;
+
+use operation_slab::operation_slab;
+use operation_slab::Token;
 
 use async_data::AsyncData;
 use winapi::HANDLE;
+use winapi::DWORD;
+use winapi::LPOVERLAPPED;
+
+use std::sync::Mutex;
 
 //-----------------------------------------------------------------------------
 pub fn write_file_async_data(file: HANDLE, async_data: Box<AsyncData>) {
-	let result = write_file_async(file,
-                             async_data.buffer.as_ptr(),
-                             async_data.buffer.len(),
-                             async_data);
-    handle_async_operation_error(result);
+	let buffer = async_data.buffer.as_ptr();
+	let buffer_size = async_data.buffer.len();
+	let (token, overlapped) = operation_slab().insert(async_data);
+	let result = write_file_async(file, buffer, buffer_size, overlapped);
+
+    handle_async_operation_error(token, result);
+}
+
+//-----------------------------------------------------------------------------
+pub fn write_file_async_data_buffer(
+		file: HANDLE,
+		buffer: *const u8,
+		buffer_size: usize,
+		async_data: Box<AsyncData>) {
+	let (token, overlapped) = operation_slab().insert(async_data);
+	let result = write_file_async(file, buffer, buffer_size, overlapped);
+
+	handle_async_operation_error(token, result);
 }
 
 //-----------------------------------------------------------------------------
@@ -30,17 +52,62 @@ pub fn read_file_async_data_buffer(
    		buffer: *const u8,
    		buffer_size: usize,
         async_data: Box<AsyncData>) {
-	let result = read_file_async(file, buffer, buffer_size, async_data);
-	handle_async_operation_error(result);
+	let (token, overlapped) = operation_slab().insert(async_data);
+	let result = read_file_async(file, buffer, buffer_size, overlapped).
+		and_then(|pending| if pending {
+			Ok(())
+		} else {
+			post_completion(overlapped)
+		});
+
+	handle_async_operation_error(token, result);
 }
         
 //-----------------------------------------------------------------------------
-fn handle_async_operation_error(result: Result<(), AsyncOperationError<AsyncData>>) {
+// The callback of the lock data is called once the lock is taken, without
+// holding an io worker meanwhile.
+pub fn lock_file_async_data(file: HANDLE, flags: DWORD, len: u64, async_data: Box<AsyncData>) {
+	let (token, overlapped) = operation_slab().insert(async_data);
+	let result = lock_file_async(file, flags, len, overlapped);
+
+	handle_async_operation_error(token, result);
+}
+
+//-----------------------------------------------------------------------------
+// Runs the task on an io worker, for operations which only have a blocking
+// API.
+pub fn post_task(task: Box<Fn(Result<(), String>)>) {
+	let async_data = Box::new(AsyncData::new_task_data(task));
+	let (token, overlapped) = operation_slab().insert(async_data);
+	let result = post_completion(overlapped);
+
+	handle_async_operation_error(token, result);
+}
+
+//-----------------------------------------------------------------------------
+// Queues a completion of 0 bytes for the operation.
+fn post_completion(overlapped: LPOVERLAPPED) -> Result<(), String> {
+	init_static_completion_port_once().
+		map_err(|error| error.to_string()).
+		and_then(|completion_port| post_queued_completion_status(completion_port, overlapped))
+}
+
+//-----------------------------------------------------------------------------
+fn handle_async_operation_error(token: Token, result: Result<(), String>) {
 	match result {
  		Ok(_) => {},
- 			Err(async_operation_error) => {
- 				let async_data = async_operation_error.overlapped_box;
- 				async_data.execute_error_callback(async_operation_error.error);
+ 			Err(error) => {
+ 				let async_data = operation_slab().remove(token).
+ 					expect("Failed operation is not in the slab");
+ 				async_data.execute_error_callback(error);
  			}
         }
-}
\ No newline at end of file
+}
+//-----------------------------------------------------------------------------
+// Runs take with the state locked and returns what it took out of it, to be
+// started by the caller once the lock is released: an operation can complete
+// right away, or fail to start and call its callback, and that callback may
+// lock the state again.
+pub fn take_locked<S, T, F: FnOnce(&mut S) -> T>(state: &Mutex<S>, take: F) -> T {
+	take(&mut *state.lock().unwrap())
+}
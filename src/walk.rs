@@ -0,0 +1,244 @@
+use tools::post_task;
+use tools::take_locked;
+
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// -----------------------------------------------------------------------------
+pub struct WalkOptions {
+    // Maximum number of directories being read at the same time.
+    pub max_concurrency: usize,
+    // Symlinks to directories are walked, each directory once.
+    pub follow_symlinks: bool,
+    // Only the entries whose path relative to the root matches are yielded,
+    // all the directories are walked anyway. Supports *, ? and **, with / as
+    // separator, and ignores the case.
+    pub glob: Option<String>,
+}
+
+// -----------------------------------------------------------------------------
+impl WalkOptions {
+    // -------------------------------------------------------------------------
+    pub fn new() -> WalkOptions {
+        WalkOptions {
+            max_concurrency: 4,
+            follow_symlinks: false,
+            glob: None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+pub struct WalkEntry {
+    pub path: PathBuf,
+    // 1 for the entries of the root.
+    pub depth: usize,
+    pub metadata: Metadata,
+}
+
+// -----------------------------------------------------------------------------
+struct WalkState {
+    pending_dirs: Vec<(PathBuf, usize)>,
+    nb_reading: usize,
+    visited_dirs: HashSet<PathBuf>,
+    done: bool,
+}
+
+// -----------------------------------------------------------------------------
+struct Walk {
+    root: PathBuf,
+    options: WalkOptions,
+    state: Mutex<WalkState>,
+    callback: Mutex<Box<Fn(Result<WalkEntry, String>)>>,
+    done: Box<Fn()>,
+}
+
+// -----------------------------------------------------------------------------
+// Walks the tree under root on the io workers. The callback receives each
+// entry, or the errors met on the way, one call at a time; done is called
+// once after the last one.
+pub fn walk<P: AsRef<Path>>(root: P,
+                            options: WalkOptions,
+                            callback: Box<Fn(Result<WalkEntry, String>)>,
+                            done: Box<Fn()>) {
+    let root = root.as_ref().to_path_buf();
+    let walk = Arc::new(Walk {
+        root: root.clone(),
+        options: options,
+        state: Mutex::new(WalkState {
+            pending_dirs: vec![(root, 0)],
+            nb_reading: 0,
+            visited_dirs: HashSet::new(),
+            done: false,
+        }),
+        callback: Mutex::new(callback),
+        done: done,
+    });
+
+    walk.first_visit(&walk.root);
+    schedule(&walk);
+}
+
+// -----------------------------------------------------------------------------
+fn schedule(walk: &Arc<Walk>) {
+    let (dirs, done) = take_locked(&walk.state, |state| {
+        let mut dirs = Vec::new();
+
+        while state.nb_reading < walk.options.max_concurrency.max(1) {
+            match state.pending_dirs.pop() {
+                Some(dir) => {
+                    state.nb_reading += 1;
+                    dirs.push(dir);
+                }
+                None => break,
+            }
+        }
+        if state.nb_reading == 0 && !state.done {
+            state.done = true;
+            (dirs, true)
+        } else {
+            (dirs, false)
+        }
+    });
+
+    for (dir, depth) in dirs {
+        let walk = walk.clone();
+
+        post_task(Box::new(move |result| {
+            match result {
+                Ok(_) => read_dir(&walk, &dir, depth),
+                Err(error) => walk.yield_entry(Err(error)),
+            }
+            walk.state.lock().unwrap().nb_reading -= 1;
+            schedule(&walk);
+        }));
+    }
+    if done {
+        (walk.done)();
+    }
+}
+
+// -----------------------------------------------------------------------------
+fn read_dir(walk: &Walk, dir: &Path, depth: usize) {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(error) => {
+            walk.yield_entry(Err(format!("Error cannot read directory {}: {}.",
+                                         dir.to_string_lossy(),
+                                         error)));
+            return;
+        }
+    };
+
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(error) => {
+                walk.yield_entry(Err(format!("Error cannot read directory {}: {}.",
+                                             dir.to_string_lossy(),
+                                             error)));
+                continue;
+            }
+        };
+        let metadata_result = if walk.options.follow_symlinks {
+            path.metadata()
+        } else {
+            path.symlink_metadata()
+        };
+        let metadata = match metadata_result {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                walk.yield_entry(Err(format!("Error cannot stat {}: {}.",
+                                             path.to_string_lossy(),
+                                             error)));
+                continue;
+            }
+        };
+
+        if metadata.is_dir() && walk.first_visit(&path) {
+            walk.state.lock().unwrap().pending_dirs.push((path.clone(), depth + 1));
+        }
+        if walk.matches(&path) {
+            walk.yield_entry(Ok(WalkEntry {
+                path: path,
+                depth: depth + 1,
+                metadata: metadata,
+            }));
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+impl Walk {
+    // -------------------------------------------------------------------------
+    fn yield_entry(&self, entry: Result<WalkEntry, String>) {
+        let callback = self.callback.lock().unwrap();
+
+        callback.as_ref()(entry);
+    }
+
+    // -------------------------------------------------------------------------
+    // Without following the symlinks a directory can only be met once.
+    fn first_visit(&self, dir: &Path) -> bool {
+        if !self.options.follow_symlinks {
+            return true;
+        }
+        match dir.canonicalize() {
+            Ok(canonical_dir) => self.state.lock().unwrap().visited_dirs.insert(canonical_dir),
+            Err(_) => false,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    fn matches(&self, path: &Path) -> bool {
+        match self.options.glob {
+            Some(ref glob) => {
+                let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
+                let relative_path: Vec<_> = relative_path.components().
+                    map(|component| component.as_os_str().to_string_lossy().into_owned()).
+                    collect();
+
+                glob_match(glob, &relative_path.join("/"))
+            }
+            None => true,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+pub(crate) fn glob_match(glob: &str, path: &str) -> bool {
+    let glob: Vec<char> = glob.chars().flat_map(|c| c.to_lowercase()).collect();
+    let path: Vec<char> = path.chars().flat_map(|c| c.to_lowercase()).collect();
+
+    match_chars(&glob, &path)
+}
+
+// -----------------------------------------------------------------------------
+fn match_chars(glob: &[char], path: &[char]) -> bool {
+    match glob.first() {
+        None => path.is_empty(),
+        Some(&'*') if glob.get(1) == Some(&'*') => {
+            // "**/" matches any number of whole directories, "**" anything.
+            if glob.get(2) == Some(&'/') {
+                (0..path.len() + 1).
+                    filter(|&i| i == 0 || path[i - 1] == '/').
+                    any(|i| match_chars(&glob[3..], &path[i..]))
+            } else {
+                (0..path.len() + 1).any(|i| match_chars(&glob[2..], &path[i..]))
+            }
+        }
+        Some(&'*') => {
+            (0..path.len() + 1).
+                take_while(|&i| i == 0 || path[i - 1] != '/').
+                any(|i| match_chars(&glob[1..], &path[i..]))
+        }
+        Some(&'?') => {
+            !path.is_empty() && path[0] != '/' && match_chars(&glob[1..], &path[1..])
+        }
+        Some(&c) => !path.is_empty() && path[0] == c && match_chars(&glob[1..], &path[1..]),
+    }
+}
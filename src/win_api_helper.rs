@@ -21,7 +21,6 @@ use winapi::INFINITE;
 use winapi::ERROR_HANDLE_EOF;
 use winapi::SYSTEM_INFO;
 use winapi::LARGE_INTEGER;
-use winapi::PLARGE_INTEGER;
 
 use kernel32::GetQueuedCompletionStatus;
 use kernel32::CreateFileW;
@@ -31,34 +30,93 @@ use kernel32::GetLastError;
 use kernel32::CreateIoCompletionPort;
 use kernel32::FormatMessageW;
 use kernel32::LocalFree;
-use kernel32::SetFilePointerEx;
-use kernel32::SetEndOfFile;
 use kernel32::GetSystemInfo;
+use kernel32::CreateEventW;
+use kernel32::SetEvent;
+use kernel32::ResetEvent;
+use kernel32::CloseHandle;
+use kernel32::PostQueuedCompletionStatus;
+use kernel32::GetFileInformationByHandleEx;
+use kernel32::GetFileSizeEx;
+use kernel32::SetFileInformationByHandle;
+use kernel32::DeviceIoControl;
+use kernel32::GetOverlappedResult;
+use kernel32::CopyFileExW;
+use kernel32::FlushFileBuffers;
+use kernel32::MoveFileExW;
+use kernel32::LockFileEx;
+use kernel32::UnlockFileEx;
+
+use winapi::MAX_PATH;
+use winapi::FALSE;
+use winapi::FILE_INFO_BY_HANDLE_CLASS;
+use winapi::OVERLAPPED;
+use winapi::ERROR_MORE_DATA;
+use winapi::PROGRESS_CONTINUE;
+use winapi::SOCKET;
+use winapi::ERROR_LOCK_VIOLATION;
+use winapi::LOCKFILE_FAIL_IMMEDIATELY;
+use winapi::FILE_RENAME_INFO;
+use winapi::FileRenameInfo;
 
 use std::path::Path;
+use std::path::PathBuf;
+use std::path::Component;
+use std::path::Prefix;
+use std::ffi::OsString;
+use std::env::current_dir;
+use std::os::windows::ffi::OsStrExt;
 use std::ptr::null_mut;
 use std::ptr::null;
+use std::ptr::copy_nonoverlapping;
 use std::mem::transmute;
+use std::mem::zeroed;
+use std::mem::size_of;
+
+// -----------------------------------------------------------------------------
+#[link(name = "mswsock")]
+extern "system" {
+    fn TransmitFile(hSocket: SOCKET,
+                    hFile: HANDLE,
+                    nNumberOfBytesToWrite: DWORD,
+                    nNumberOfBytesPerSend: DWORD,
+                    lpOverlapped: LPOVERLAPPED,
+                    lpTransmitBuffers: LPVOID,
+                    dwReserved: DWORD)
+                    -> BOOL;
+}
+
+//-----------------------------------------------------------------------------
+#[derive(Copy, Clone)]
+pub struct WinHandle
+{
+	pub handle: HANDLE
+}
+
+unsafe impl Sync for WinHandle {}
+unsafe impl Send for WinHandle {}
 
 // -----------------------------------------------------------------------------
 pub fn create_file_async<P: AsRef<Path>>(path: P,
                                          desired_access: DWORD,
+                                         share_mode: DWORD,
                                          creation_disposition: DWORD)
                                          -> Result<HANDLE, String> {
     unsafe {
-        let path_str = path.as_ref().to_string_lossy().to_string(); 
-		let filename = string_to_utf16(&path_str);
+        let filename = try!(path_to_utf16(path.as_ref()));
 
-        let file = CreateFileW(filename,
+        let file = CreateFileW(filename.as_ptr(),
                                desired_access,
-                               0,
+                               share_mode,
                                null_mut(),
                                creation_disposition,
                                FILE_ATTRIBUTE_NORMAL | FILE_FLAG_OVERLAPPED |
                                FILE_FLAG_NO_BUFFERING,
                                null_mut());
         if file == INVALID_HANDLE_VALUE {
-            Err(get_create_file_async_error(&path_str, GetLastError(), creation_disposition))
+            Err(get_create_file_async_error(&path.as_ref().to_string_lossy(),
+                                            GetLastError(),
+                                            creation_disposition))
         } else {
             Ok(file)
         }
@@ -66,49 +124,62 @@ pub fn create_file_async<P: AsRef<Path>>(path: P,
 }
 
 // -----------------------------------------------------------------------------
-pub struct AsyncOperationError<T> {
-	pub error: String,
-	pub overlapped_box: Box<T>
-}
-
-// -----------------------------------------------------------------------------
-pub fn write_file_async<T>(file: HANDLE,
+pub fn write_file_async(file: HANDLE,
                         buffer: *const u8,
                         buffer_size: usize,
-                        overlapped_box: Box<T>)
-                        -> Result<(), AsyncOperationError<T>> {
+                        overlapped: LPOVERLAPPED)
+                        -> Result<(), String> {
     let mut bytes_written: DWORD = 0;
 
     unsafe {
-    	println!("Before WriteFile");
-
-    	let overlapped = transmute::<Box<T>, LPOVERLAPPED>(overlapped_box);
         let status = WriteFile(file,
                      buffer as LPCVOID,
                      buffer_size as DWORD,
                      &mut bytes_written,
                      overlapped);
-       check_async_operation::<T>(overlapped, status, "write", GetLastError())
+        check_async_operation(status, "write", GetLastError())
     }
 }
 
 
 // -----------------------------------------------------------------------------
-pub fn read_file_async<T>(file: HANDLE,
+// Locks the range starting at the offset of the overlapped, it completes
+// through the completion port once the lock is taken.
+pub fn lock_file_async(file: HANDLE,
+                       flags: DWORD,
+                       len: u64,
+                       overlapped: LPOVERLAPPED)
+                       -> Result<(), String> {
+    unsafe {
+        let status = LockFileEx(file, flags, 0, len as DWORD, (len >> 32) as DWORD, overlapped);
+
+        check_async_operation(status, "lock", GetLastError())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Returns false when the read starts at the end of the file: it then fails
+// synchronously and no completion packet is queued.
+pub fn read_file_async(file: HANDLE,
                        buffer: *const u8,
                        buffer_size: usize,
-                       overlapped_box: Box<T>)
-                       -> Result<(), AsyncOperationError<T>> {
+                       overlapped: LPOVERLAPPED)
+                       -> Result<bool, String> {
     let mut bytes_read: DWORD = 0;
 
     unsafe {
-    	let overlapped = transmute::<_, LPOVERLAPPED>(overlapped_box);
         let status = ReadFile(file,
                     buffer as LPVOID,
                     buffer_size as DWORD,
                     &mut bytes_read,
                     overlapped);
-		check_async_operation::<T>(overlapped, status, "read", GetLastError())
+        let error_id = GetLastError();
+
+        if status != TRUE && error_id == ERROR_HANDLE_EOF {
+            Ok(false)
+        } else {
+			check_async_operation(status, "read", error_id).map(|_| true)
+        }
     }
 }
 
@@ -131,19 +202,34 @@ pub fn create_io_completion_port(file_handle: HANDLE,
     }
 }
 
+// -----------------------------------------------------------------------------
+pub fn post_queued_completion_status(completion_port: HANDLE,
+                                     overlapped: LPOVERLAPPED)
+                                     -> Result<(), String> {
+    unsafe {
+        if PostQueuedCompletionStatus(completion_port, 0, 0, overlapped) == 0 {
+            Err(get_error_message("PostQueuedCompletionStatus", GetLastError()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 // -----------------------------------------------------------------------------
 pub struct CompletionStatus
 {
 	pub nb_bytes_transferred: DWORD, 
 	pub overlapped: LPOVERLAPPED,
-	pub end_of_file: bool
+	pub end_of_file: bool,
+	// The operation of the overlapped failed after it was queued.
+	pub error: Option<String>,
 }
 
 // -----------------------------------------------------------------------------
 pub fn get_queued_completion_status(handle: HANDLE) -> Result<CompletionStatus, String> {
 	let mut completion_key: ULONG_PTR = 0;
 	let mut completion_status = CompletionStatus { nb_bytes_transferred: 0, 
-		overlapped: null_mut(), end_of_file: false}; 
+		overlapped: null_mut(), end_of_file: false, error: None}; 
 	
 	unsafe {	
 		if GetQueuedCompletionStatus(
@@ -157,6 +243,9 @@ pub fn get_queued_completion_status(handle: HANDLE) -> Result<CompletionStatus,
 				if error_id == ERROR_HANDLE_EOF {
 					completion_status.end_of_file = true;
 				} 
+				else if !completion_status.overlapped.is_null() {
+					completion_status.error = Some(get_error_message("Async operation", error_id));
+				}
 				else
 				{
 					return Err(get_error_message("GetQueuedCompletionStatus", error_id));
@@ -167,36 +256,448 @@ pub fn get_queued_completion_status(handle: HANDLE) -> Result<CompletionStatus,
 }
 
 // -----------------------------------------------------------------------------
-pub fn set_file_pointer_ex(
+// T must be the structure matching information_class.
+pub fn get_file_information_by_handle_ex<T>(file: HANDLE,
+                                            information_class: FILE_INFO_BY_HANDLE_CLASS)
+                                            -> Result<T, String> {
+	unsafe {
+		let mut information: T = zeroed();
+
+		if GetFileInformationByHandleEx(file,
+										information_class,
+										&mut information as *mut T as LPVOID,
+										size_of::<T>() as DWORD) == 0 {
+			Err(get_error_message("GetFileInformationByHandleEx", GetLastError()))
+		} else {
+			Ok(information)
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// T must be the structure matching information_class.
+pub fn set_file_information_by_handle<T>(file: HANDLE,
+                                         information_class: FILE_INFO_BY_HANDLE_CLASS,
+                                         information: &mut T)
+                                         -> Result<(), String> {
+	unsafe {
+		if SetFileInformationByHandle(file,
+									  information_class,
+									  information as *mut T as LPVOID,
+									  size_of::<T>() as DWORD) == 0 {
+			Err(get_error_message("SetFileInformationByHandle", GetLastError()))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Renames the file on the same volume, the handle needs the DELETE access.
+pub fn set_file_rename_info(file: HANDLE, path: &Path, replace_if_exists: bool) -> Result<(), String> {
+	let absolute_path = if path.is_absolute() {
+		path.to_path_buf()
+	} else {
+		try!(current_dir().map_err(|error| format!("Error cannot rename file: {}.", error))).
+			join(path)
+	};
+	let file_name = try!(path_to_utf16(&absolute_path));
+	let name_size = (file_name.len() - 1) * size_of::<u16>();
+	let info_size = size_of::<FILE_RENAME_INFO>() + file_name.len() * size_of::<u16>();
+	// u64 keeps the structure aligned.
+	let mut buffer: Vec<u64> = vec![0; (info_size + 7) / 8];
+
+	unsafe {
+		let info = buffer.as_mut_ptr() as *mut FILE_RENAME_INFO;
+
+		(*info).ReplaceIfExists = if replace_if_exists { TRUE } else { FALSE };
+		(*info).RootDirectory = null_mut();
+		(*info).FileNameLength = name_size as DWORD;
+		copy_nonoverlapping(file_name.as_ptr(),
+							(*info).FileName.as_mut_ptr(),
+							file_name.len());
+		if SetFileInformationByHandle(file,
+									  FileRenameInfo,
+									  info as LPVOID,
+									  info_size as DWORD) == 0 {
+			Err(get_error_message("SetFileInformationByHandle", GetLastError()))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+pub fn get_file_size_ex(file: HANDLE) -> Result<u64, String> {
+	let mut size: LARGE_INTEGER = 0;
+
+	unsafe {
+		if GetFileSizeEx(file, &mut size) == 0 {
+			Err(get_error_message("GetFileSizeEx", GetLastError()))
+		} else {
+			Ok(size as u64)
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+pub fn create_event(manual_reset: bool) -> Result<HANDLE, String> {
+	unsafe {
+		let event = CreateEventW(null_mut(),
+								 if manual_reset { TRUE } else { FALSE },
+								 FALSE,
+								 null());
+		if event == null_mut() {
+			Err(get_error_message("CreateEvent", GetLastError()))
+		} else {
+			Ok(event)
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+pub fn set_event(event: HANDLE) -> Result<(), String> {
+	unsafe {
+		if SetEvent(event) == 0 {
+			Err(get_error_message("SetEvent", GetLastError()))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+pub fn reset_event(event: HANDLE) -> Result<(), String> {
+	unsafe {
+		if ResetEvent(event) == 0 {
+			Err(get_error_message("ResetEvent", GetLastError()))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+pub fn close_handle(handle: HANDLE) -> Result<(), String> {
+	unsafe {
+		if CloseHandle(handle) == 0 {
+			Err(get_error_message("CloseHandle", GetLastError()))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Waits on an io worker for an overlapped operation on a file associated with
+// the completion port. The low bit set on the event keeps the completion out
+// of the port.
+struct BlockingOverlapped {
+	overlapped: OVERLAPPED,
+	event: HANDLE,
+}
+
+// -----------------------------------------------------------------------------
+impl BlockingOverlapped {
+	// -------------------------------------------------------------------------
+	fn new(offset: u64) -> Result<BlockingOverlapped, String> {
+		let event = try!(create_event(true));
+
+		Ok(BlockingOverlapped {
+			overlapped: OVERLAPPED {
+				Internal: 0,
+				InternalHigh: 0,
+				Offset: offset as DWORD,
+				OffsetHigh: (offset >> 32) as DWORD,
+				hEvent: (event as usize | 1) as HANDLE,
+			},
+			event: event,
+		})
+	}
+
+	// -------------------------------------------------------------------------
+	// status and error_id are the ones of the call which used the overlapped,
+	// the error is the system error id.
+	fn wait(&mut self, file: HANDLE, status: BOOL, error_id: DWORD) -> Result<DWORD, DWORD> {
+		let mut nb_bytes_transferred: DWORD = 0;
+
+		if status != TRUE && error_id != ERROR_IO_PENDING {
+			return Err(error_id);
+		}
+		unsafe {
+			if GetOverlappedResult(file, &mut self.overlapped, &mut nb_bytes_transferred, TRUE) == 0 {
+				Err(GetLastError())
+			} else {
+				Ok(nb_bytes_transferred)
+			}
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for BlockingOverlapped {
+	fn drop(&mut self) {
+		let _ = close_handle(self.event);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// A read started from an io worker, which can do something else before
+// waiting for it. The buffer must stay valid until the read is dropped.
+pub struct PendingRead {
+	overlapped: Box<BlockingOverlapped>,
 	file: HANDLE,
-    distance_to_move: LARGE_INTEGER,
-    new_file_pointer: PLARGE_INTEGER,
-    move_method: DWORD) -> Result<(), String>
-{
+	status: Option<(BOOL, DWORD)>,
+}
+
+// -----------------------------------------------------------------------------
+impl PendingRead {
+	// -------------------------------------------------------------------------
+	// Returns 0 at the end of the file.
+	pub fn wait(mut self) -> Result<usize, String> {
+		self.complete()
+	}
+
+	// -------------------------------------------------------------------------
+	fn complete(&mut self) -> Result<usize, String> {
+		match self.status.take() {
+			Some((status, error_id)) => match self.overlapped.wait(self.file, status, error_id) {
+				Ok(nb_bytes_read) => Ok(nb_bytes_read as usize),
+				Err(ERROR_HANDLE_EOF) => Ok(0),
+				Err(error_id) => Err(get_error_message("ReadFile", error_id)),
+			},
+			None => Ok(0),
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+impl Drop for PendingRead {
+	fn drop(&mut self) {
+		let _ = self.complete();
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers.
+pub fn start_read_file(file: HANDLE,
+                       buffer: *mut u8,
+                       buffer_size: usize,
+                       offset: u64)
+                       -> Result<PendingRead, String> {
+	let mut overlapped = Box::new(try!(BlockingOverlapped::new(offset)));
+
+	unsafe {
+		let status = ReadFile(file,
+							  buffer as LPVOID,
+							  buffer_size as DWORD,
+							  null_mut(),
+							  &mut overlapped.overlapped);
+		Ok(PendingRead {
+			overlapped: overlapped,
+			file: file,
+			status: Some((status, GetLastError())),
+		})
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers. Returns 0 at the end of the file.
+pub fn read_file_blocking(file: HANDLE,
+                          buffer: *mut u8,
+                          buffer_size: usize,
+                          offset: u64)
+                          -> Result<usize, String> {
+	try!(start_read_file(file, buffer, buffer_size, offset)).wait()
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers.
+pub fn write_file_blocking(file: HANDLE,
+                           buffer: *const u8,
+                           buffer_size: usize,
+                           offset: u64)
+                           -> Result<usize, String> {
+	let mut overlapped = try!(BlockingOverlapped::new(offset));
+
+	unsafe {
+		let status = WriteFile(file,
+							   buffer as LPCVOID,
+							   buffer_size as DWORD,
+							   null_mut(),
+							   &mut overlapped.overlapped);
+		overlapped.wait(file, status, GetLastError()).
+			map(|nb_bytes_written| nb_bytes_written as usize).
+			map_err(|error_id| get_error_message("WriteFile", error_id))
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers. Returns the size of the output and whether the output
+// buffer was too small to hold all of it.
+pub fn device_io_control(file: HANDLE,
+                         control_code: DWORD,
+                         in_buffer: LPVOID,
+                         in_buffer_size: usize,
+                         out_buffer: LPVOID,
+                         out_buffer_size: usize)
+                         -> Result<(usize, bool), String> {
+	let mut overlapped = try!(BlockingOverlapped::new(0));
+
+	unsafe {
+		let status = DeviceIoControl(file,
+									 control_code,
+									 in_buffer,
+									 in_buffer_size as DWORD,
+									 out_buffer,
+									 out_buffer_size as DWORD,
+									 null_mut(),
+									 &mut overlapped.overlapped);
+		match overlapped.wait(file, status, GetLastError()) {
+			Ok(nb_bytes_returned) => Ok((nb_bytes_returned as usize, false)),
+			Err(ERROR_MORE_DATA) => {
+				Ok((overlapped.overlapped.InternalHigh as usize, true))
+			}
+			Err(error_id) => Err(get_error_message("DeviceIoControl", error_id)),
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers. Sends the file range from the kernel, the socket must
+// not be associated with the completion port.
+pub fn transmit_file(socket: SOCKET,
+                     file: HANDLE,
+                     offset: u64,
+                     nb_bytes_to_write: u32)
+                     -> Result<usize, String> {
+	let mut overlapped = try!(BlockingOverlapped::new(offset));
+
 	unsafe {
-		if SetFilePointerEx(
-					file,
-				    distance_to_move,
-				    new_file_pointer,
-				    move_method) == 0 {
-			Err(get_error_message("SetFilePointer", GetLastError()))
-		} else {    
+		let status = TransmitFile(socket,
+								  file,
+								  nb_bytes_to_write,
+								  0,
+								  &mut overlapped.overlapped,
+								  null_mut(),
+								  0);
+		overlapped.wait(socket as HANDLE, status, GetLastError()).
+			map(|nb_bytes_sent| nb_bytes_sent as usize).
+			map_err(|error_id| get_error_message("TransmitFile", error_id))
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Blocks until the lock is acquired, only for io workers, unless the flags
+// contain LOCKFILE_FAIL_IMMEDIATELY: it then returns false if the range is
+// locked by another handle.
+pub fn lock_file_ex(file: HANDLE, flags: DWORD, offset: u64, len: u64) -> Result<bool, String> {
+	let mut overlapped = try!(BlockingOverlapped::new(offset));
+
+	unsafe {
+		let status = LockFileEx(file,
+								flags,
+								0,
+								len as DWORD,
+								(len >> 32) as DWORD,
+								&mut overlapped.overlapped);
+		match overlapped.wait(file, status, GetLastError()) {
+			Ok(_) => Ok(true),
+			Err(ERROR_LOCK_VIOLATION) if flags & LOCKFILE_FAIL_IMMEDIATELY != 0 => Ok(false),
+			Err(error_id) => Err(get_error_message("LockFileEx", error_id)),
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// The range must be the one of a lock.
+pub fn unlock_file_ex(file: HANDLE, offset: u64, len: u64) -> Result<(), String> {
+	let mut overlapped = try!(BlockingOverlapped::new(offset));
+
+	unsafe {
+		let status = UnlockFileEx(file,
+								  0,
+								  len as DWORD,
+								  (len >> 32) as DWORD,
+								  &mut overlapped.overlapped);
+		overlapped.wait(file, status, GetLastError()).
+			map(|_| ()).
+			map_err(|error_id| get_error_message("UnlockFileEx", error_id))
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Only for io workers.
+pub fn flush_file_buffers(file: HANDLE) -> Result<(), String> {
+	unsafe {
+		if FlushFileBuffers(file) == 0 {
+			Err(get_error_message("FlushFileBuffers", GetLastError()))
+		} else {
 			Ok(())
 		}
 	}
 }
 
 // -----------------------------------------------------------------------------
-pub fn set_end_of_file(file: HANDLE) -> Result<(), String> {
+pub fn move_file_ex(existing_path: &Path, new_path: &Path, flags: DWORD) -> Result<(), String> {
+	let existing_filename = try!(path_to_utf16(existing_path));
+	let new_filename = try!(path_to_utf16(new_path));
+
 	unsafe {
-		if SetEndOfFile(file) == 0 {
-			Err(get_error_message("SetEndOfFile", GetLastError()))
-		} else {    
+		if MoveFileExW(existing_filename.as_ptr(), new_filename.as_ptr(), flags) == 0 {
+			Err(get_error_message(&format!("cannot move {} to {}",
+										   existing_path.to_string_lossy(),
+										   new_path.to_string_lossy()),
+								  GetLastError()))
+		} else {
 			Ok(())
 		}
 	}
 }
 
+// -----------------------------------------------------------------------------
+// Only for io workers. The progress receives the bytes copied and the total.
+pub fn copy_file_ex(existing_path: &Path,
+                    new_path: &Path,
+                    progress: &Fn(u64, u64))
+                    -> Result<(), String> {
+	let existing_filename = try!(path_to_utf16(existing_path));
+	let new_filename = try!(path_to_utf16(new_path));
+
+	unsafe {
+		if CopyFileExW(existing_filename.as_ptr(),
+					   new_filename.as_ptr(),
+					   Some(copy_progress_routine),
+					   &progress as *const &Fn(u64, u64) as LPVOID,
+					   null_mut(),
+					   0) == 0 {
+			Err(get_error_message(&format!("cannot copy {} to {}",
+										   existing_path.to_string_lossy(),
+										   new_path.to_string_lossy()),
+								  GetLastError()))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+unsafe extern "system" fn copy_progress_routine(total_file_size: LARGE_INTEGER,
+                                                total_bytes_transferred: LARGE_INTEGER,
+                                                _: LARGE_INTEGER,
+                                                _: LARGE_INTEGER,
+                                                _: DWORD,
+                                                _: DWORD,
+                                                _: HANDLE,
+                                                _: HANDLE,
+                                                data: LPVOID)
+                                                -> DWORD {
+	let progress = &*(data as *const &Fn(u64, u64));
+
+	progress(total_bytes_transferred as u64, total_file_size as u64);
+	PROGRESS_CONTINUE
+}
+
 // -----------------------------------------------------------------------------
 pub fn get_system_info() -> SYSTEM_INFO {
 	let mut system_info = SYSTEM_INFO{
@@ -219,10 +720,62 @@ pub fn get_system_info() -> SYSTEM_INFO {
 }
 
 // -----------------------------------------------------------------------------
-fn string_to_utf16(str: &String) -> *const u16 {
-	let mut buffer: Vec<u16> = str.encode_utf16().collect();
+// The returned buffer is null terminated and must outlive the pointer given to
+// the API.
+fn path_to_utf16(path: &Path) -> Result<Vec<u16>, String> {
+	let mut buffer: Vec<u16> = try!(to_long_path(path)).as_os_str().encode_wide().collect();
 	buffer.push(0);
-	buffer.as_ptr()
+	Ok(buffer)
+}
+
+// -----------------------------------------------------------------------------
+// Paths of MAX_PATH characters or more once made absolute are only accepted
+// with the \\?\ prefix, which also disables any normalization: resolve "."
+// and ".." ourselves.
+fn to_long_path(path: &Path) -> Result<PathBuf, String> {
+	let absolute_path = if path.is_absolute() {
+		path.to_path_buf()
+	} else {
+		try!(current_dir().map_err(|error| {
+			format!("Error cannot resolve {}: {}.", path.to_string_lossy(), error)
+		})).join(path)
+	};
+
+	if absolute_path.as_os_str().encode_wide().count() < MAX_PATH {
+		return Ok(path.to_path_buf());
+	}
+
+	let mut long_path = OsString::new();
+	let mut names = Vec::new();
+
+	for component in absolute_path.components() {
+		match component {
+			Component::Prefix(prefix) => match prefix.kind() {
+				Prefix::Disk(_) => {
+					long_path.push(r"\\?\");
+					long_path.push(prefix.as_os_str());
+				}
+				Prefix::UNC(server, share) => {
+					long_path.push(r"\\?\UNC\");
+					long_path.push(server);
+					long_path.push(r"\");
+					long_path.push(share);
+				}
+				_ => return Ok(absolute_path.clone()),
+			},
+			Component::RootDir | Component::CurDir => {}
+			Component::ParentDir => {
+				names.pop();
+			}
+			Component::Normal(name) => names.push(name),
+		}
+	}
+
+	for name in names {
+		long_path.push(r"\");
+		long_path.push(name);
+	}
+	Ok(PathBuf::from(long_path))
 }
 
 // -----------------------------------------------------------------------------
@@ -239,29 +792,19 @@ fn utf16_tostring(ptr: *mut u16) -> String {
 }
 
 // -----------------------------------------------------------------------------
-fn check_async_operation<T>(
-	overlapped: LPOVERLAPPED,
+// A completion packet is queued even when the operation completes
+// synchronously, so only a failure means the OS did not take the operation.
+fn check_async_operation(
 	status: BOOL,
 	operation_type: &str,
-	error_id: DWORD) -> Result<(), AsyncOperationError<T>> {
-	if status != TRUE && error_id == ERROR_IO_PENDING {
+	error_id: DWORD) -> Result<(), String> {
+	if status == TRUE || error_id == ERROR_IO_PENDING {
 		Ok(())
 	} else {
-	    let error = if status == TRUE {
-			format!("Error {} is synchronous", operation_type)            
-        } else {
-            get_error_message(
-            		&format!(
-            			"Error when performaing {} async", 
-            			operation_type), error_id)
-        };
-        
-        unsafe {
-			Err(AsyncOperationError{ 
-				error: error, 
-				overlapped_box: transmute::<_, Box<T>>(overlapped)
-			})
-        }
+		Err(get_error_message(
+				&format!(
+					"Error when performaing {} async",
+					operation_type), error_id))
     }
 }
 